@@ -17,7 +17,7 @@ pub fn get(conn: &Connection, name: &'static str) -> Result<xproto::Atom> {
     match map.entry(name) {
         Entry::Occupied(entry) => Ok(*entry.get()),
         Entry::Vacant(entry) => {
-            let interned = xproto::intern_atom(conn, true, name).get_reply()?.atom();
+            let interned = xproto::intern_atom(conn, false, name).get_reply()?.atom();
             Ok(*entry.insert(interned))
         }
     }
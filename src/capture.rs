@@ -0,0 +1,339 @@
+use anyhow::{anyhow, Result};
+use nix::libc;
+use std::io;
+use std::ptr;
+use std::slice;
+use xcb::base::Connection;
+use xcb::shm;
+use xcb::xproto;
+
+use crate::color::{self, ARGB};
+use crate::util::has_extension;
+
+/// A rect on the root window: x, y, width and height.
+pub type Rect = (i16, i16, u16, u16);
+
+// Pixels fetched around the requested rect on every side when filling a tile
+const TILE_MARGIN: i32 = 128;
+
+/// A source of screen pixels for the magnifier and the final pick.
+pub trait Capture {
+    /// Returns the pixels inside `rect` (row by row). The rect must lie within the root window.
+    fn capture(&mut self, conn: &Connection, rect: Rect) -> Result<Vec<ARGB>>;
+
+    /// Returns how far around the requested rects pixels are fetched and kept,
+    /// anything drawn there by us would end up in later captures.
+    fn margin(&self) -> i32 {
+        0
+    }
+
+    /// Called when the content inside `area` has changed on screen.
+    fn invalidate(&mut self, _area: Rect) {}
+
+    /// Frees the resources used on the X server.
+    fn destroy(&mut self, _conn: &Connection) {}
+}
+
+/// Returns whether `outer` fully contains `inner`.
+pub fn contains(outer: Rect, inner: Rect) -> bool {
+    let (ox, oy, ow, oh) = (
+        outer.0 as i32,
+        outer.1 as i32,
+        outer.2 as i32,
+        outer.3 as i32,
+    );
+    let (ix, iy, iw, ih) = (
+        inner.0 as i32,
+        inner.1 as i32,
+        inner.2 as i32,
+        inner.3 as i32,
+    );
+    ix >= ox && iy >= oy && ix + iw <= ox + ow && iy + ih <= oy + oh
+}
+
+/// Returns whether the two rects overlap.
+pub fn intersects(a: Rect, b: Rect) -> bool {
+    let (ax, ay, aw, ah) = (a.0 as i32, a.1 as i32, a.2 as i32, a.3 as i32);
+    let (bx, by, bw, bh) = (b.0 as i32, b.1 as i32, b.2 as i32, b.3 as i32);
+    ax < bx + bw && bx < ax + aw && ay < by + bh && by < ay + ah
+}
+
+/// Returns the part of `a` that lies inside `b`, if any.
+pub fn intersection(a: Rect, b: Rect) -> Option<Rect> {
+    if !intersects(a, b) {
+        return None;
+    }
+    let x = a.0.max(b.0);
+    let y = a.1.max(b.1);
+    let right = (a.0 as i32 + a.2 as i32).min(b.0 as i32 + b.2 as i32);
+    let bottom = (a.1 as i32 + a.3 as i32).min(b.1 as i32 + b.3 as i32);
+    Some((x, y, (right - x as i32) as u16, (bottom - y as i32) as u16))
+}
+
+// Grows `rect` by `margin` on every side without leaving a `width` by `height` screen
+fn expand(rect: Rect, margin: i32, width: u16, height: u16) -> Rect {
+    let x = (rect.0 as i32 - margin).max(0);
+    let y = (rect.1 as i32 - margin).max(0);
+    let right = (rect.0 as i32 + rect.2 as i32 + margin).min(width.into());
+    let bottom = (rect.1 as i32 + rect.3 as i32 + margin).min(height.into());
+    (x as i16, y as i16, (right - x) as u16, (bottom - y) as u16)
+}
+
+// Copies `rect` out of `pixels`, an image that is `stride` pixels wide
+fn copy_rect(pixels: &[ARGB], stride: usize, (x, y, width, height): Rect) -> Vec<ARGB> {
+    let (x, y) = (x as usize, y as usize);
+    let (width, height) = (width as usize, height as usize);
+
+    let mut copy = Vec::with_capacity(width * height);
+    for row in y..y + height {
+        let start = row * stride + x;
+        copy.extend_from_slice(&pixels[start..start + width]);
+    }
+
+    copy
+}
+
+/// Reads pixels from the X server every time they are requested.
+pub struct LiveCapture {
+    root: xproto::Window,
+}
+
+impl LiveCapture {
+    pub fn new(screen: &xproto::Screen) -> Self {
+        LiveCapture {
+            root: screen.root(),
+        }
+    }
+}
+
+impl Capture for LiveCapture {
+    fn capture(&mut self, conn: &Connection, rect: Rect) -> Result<Vec<ARGB>> {
+        color::window_rect(conn, self.root, rect)
+    }
+}
+
+// Size of the first shared memory segment, it grows when larger rects are requested
+const INITIAL_SEGMENT_SIZE: usize = 64 * 1024;
+
+// A System V shared memory segment attached both to us and to the X server
+struct Segment {
+    seg: shm::Seg,
+    addr: *const u8,
+    size: usize,
+}
+
+impl Segment {
+    fn new(conn: &Connection, size: usize) -> Result<Self> {
+        unsafe {
+            let id = libc::shmget(libc::IPC_PRIVATE, size, libc::IPC_CREAT | 0o600);
+            if id == -1 {
+                return Err(io::Error::last_os_error().into());
+            }
+
+            let addr = libc::shmat(id, ptr::null(), libc::SHM_RDONLY);
+            if addr as isize == -1 {
+                let err = io::Error::last_os_error();
+                libc::shmctl(id, libc::IPC_RMID, ptr::null_mut());
+                return Err(err.into());
+            }
+
+            // this fails when the X server can't reach our memory, e.g. over SSH
+            let seg = conn.generate_id();
+            let attached = shm::attach_checked(conn, seg, id as u32, false).request_check();
+
+            // the segment is destroyed once both sides detach, even if we exit abruptly
+            libc::shmctl(id, libc::IPC_RMID, ptr::null_mut());
+
+            if let Err(err) = attached {
+                libc::shmdt(addr);
+                return Err(err.into());
+            }
+
+            Ok(Segment {
+                seg,
+                addr: addr as *const u8,
+                size,
+            })
+        }
+    }
+
+    fn detach(self, conn: &Connection) {
+        shm::detach(conn, self.seg);
+    }
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        unsafe {
+            libc::shmdt(self.addr as *const libc::c_void);
+        }
+    }
+}
+
+/// Reads pixels from the X server through shared memory (MIT-SHM), which
+/// avoids sending every image over the X connection.
+pub struct ShmCapture {
+    root: xproto::Window,
+    segment: Segment,
+}
+
+impl ShmCapture {
+    /// Returns `None` if the X server doesn't support MIT-SHM or can't share
+    /// memory with us.
+    pub fn new(conn: &Connection, screen: &xproto::Screen) -> Option<Self> {
+        if !has_extension(conn, shm::id()) {
+            return None;
+        }
+
+        shm::query_version(conn).get_reply().ok()?;
+        let segment = Segment::new(conn, INITIAL_SEGMENT_SIZE).ok()?;
+
+        Some(ShmCapture {
+            root: screen.root(),
+            segment,
+        })
+    }
+}
+
+impl Capture for ShmCapture {
+    fn capture(&mut self, conn: &Connection, (x, y, width, height): Rect) -> Result<Vec<ARGB>> {
+        let size = width as usize * height as usize * 4;
+        if size > self.segment.size {
+            let segment = Segment::new(conn, size.next_power_of_two())?;
+            std::mem::replace(&mut self.segment, segment).detach(conn);
+        }
+
+        let reply = shm::get_image(
+            conn,
+            self.root,
+            x,
+            y,
+            width,
+            height,
+            u32::MAX,
+            xproto::IMAGE_FORMAT_Z_PIXMAP as u8,
+            self.segment.seg,
+            0,
+        )
+        .get_reply()?;
+
+        let len = reply.size() as usize;
+        if len > self.segment.size {
+            return Err(anyhow!("Image does not fit in the shared memory segment"));
+        }
+
+        // the reply is only sent once the server has finished writing the image
+        let data = unsafe { slice::from_raw_parts(self.segment.addr, len) };
+        color::decode_pixels(reply.depth(), data)
+    }
+
+    fn destroy(&mut self, conn: &Connection) {
+        shm::detach(conn, self.segment.seg);
+    }
+}
+
+/// Reads pixels from a snapshot of the whole root window taken up front, so
+/// transient windows (menus, tooltips) can be picked after they disappear.
+pub struct FrozenCapture {
+    width: usize,
+    pixels: Vec<ARGB>,
+}
+
+impl FrozenCapture {
+    pub fn new(conn: &Connection, screen: &xproto::Screen) -> Result<Self> {
+        let width = screen.width_in_pixels();
+        let height = screen.height_in_pixels();
+        let pixels = color::window_rect(conn, screen.root(), (0, 0, width, height))?;
+
+        Ok(FrozenCapture {
+            width: width.into(),
+            pixels,
+        })
+    }
+}
+
+impl Capture for FrozenCapture {
+    fn capture(&mut self, _conn: &Connection, rect: Rect) -> Result<Vec<ARGB>> {
+        Ok(copy_rect(&self.pixels, self.width, rect))
+    }
+}
+
+/// Fetches a larger tile around the requested rect and serves later requests
+/// inside it from memory, which helps when every request crosses a slow
+/// network. The tile is refetched once a request leaves it or once the content
+/// inside it changes, so this needs damage to be reported through `invalidate`.
+pub struct TileCapture<C> {
+    inner: C,
+    width: u16,
+    height: u16,
+    tile: Option<(Rect, Vec<ARGB>)>,
+}
+
+impl<C: Capture> TileCapture<C> {
+    pub fn new(inner: C, screen: &xproto::Screen) -> Self {
+        TileCapture {
+            inner,
+            width: screen.width_in_pixels(),
+            height: screen.height_in_pixels(),
+            tile: None,
+        }
+    }
+}
+
+impl<C: Capture> Capture for TileCapture<C> {
+    fn capture(&mut self, conn: &Connection, rect: Rect) -> Result<Vec<ARGB>> {
+        let (tile, pixels) = match self.tile.take() {
+            Some((tile, pixels)) if contains(tile, rect) => (tile, pixels),
+            _ => {
+                let tile = expand(rect, TILE_MARGIN, self.width, self.height);
+                (tile, self.inner.capture(conn, tile)?)
+            }
+        };
+
+        let offset = (rect.0 - tile.0, rect.1 - tile.1, rect.2, rect.3);
+        let copy = copy_rect(&pixels, tile.2.into(), offset);
+        self.tile = Some((tile, pixels));
+
+        Ok(copy)
+    }
+
+    fn margin(&self) -> i32 {
+        TILE_MARGIN + self.inner.margin()
+    }
+
+    fn invalidate(&mut self, area: Rect) {
+        if self
+            .tile
+            .as_ref()
+            .map_or(false, |(tile, _)| intersects(*tile, area))
+        {
+            self.tile = None;
+        }
+        self.inner.invalidate(area);
+    }
+
+    fn destroy(&mut self, conn: &Connection) {
+        self.inner.destroy(conn);
+    }
+}
+
+#[test]
+fn test_tile_rects() {
+    assert!(contains((0, 0, 10, 10), (2, 2, 8, 8)));
+    assert!(!contains((0, 0, 10, 10), (2, 2, 9, 8)));
+    assert!(intersects((0, 0, 10, 10), (9, 9, 5, 5)));
+    assert!(!intersects((0, 0, 10, 10), (10, 0, 5, 5)));
+    assert_eq!(
+        intersection((-5, 2, 10, 10), (0, 0, 8, 8)),
+        Some((0, 2, 5, 6))
+    );
+    assert_eq!(intersection((0, 0, 10, 10), (10, 0, 5, 5)), None);
+
+    // tiles are clamped to the screen
+    assert_eq!(expand((5, 5, 3, 3), 10, 100, 100), (0, 0, 18, 18));
+    assert_eq!(expand((90, 50, 3, 3), 10, 100, 100), (80, 40, 20, 23));
+
+    let pixels: Vec<ARGB> = (0..12).map(|i| ARGB::new(0xff, i, 0, 0)).collect();
+    let copy = copy_rect(&pixels, 4, (1, 1, 2, 2));
+    assert_eq!(copy.iter().map(|c| c.r).collect::<Vec<_>>(), [5, 6, 9, 10]);
+}
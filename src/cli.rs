@@ -1,4 +1,4 @@
-use clap::{App, AppSettings, Arg};
+use clap::{App, AppSettings, Arg, SubCommand};
 
 pub fn get_cli() -> App<'static, 'static> {
     App::new(env!("CARGO_PKG_NAME"))
@@ -14,7 +14,8 @@ pub fn get_cli() -> App<'static, 'static> {
                 .value_name("NAME")
                 .help("Output format (defaults to hex)")
                 .possible_values(&["hex", "HEX", "hex!", "HEX!", "plain", "rgb"])
-                .conflicts_with("custom"),
+                .conflicts_with("custom")
+                .global(true),
         )
         .arg(
             Arg::with_name("custom")
@@ -23,7 +24,8 @@ pub fn get_cli() -> App<'static, 'static> {
                 .takes_value(true)
                 .value_name("FORMAT")
                 .help("Custom output format")
-                .conflicts_with("format"),
+                .conflicts_with("format")
+                .global(true),
         )
         .arg(
             Arg::with_name("selection")
@@ -32,9 +34,37 @@ pub fn get_cli() -> App<'static, 'static> {
                 .takes_value(true)
                 .value_name("SELECTION")
                 .min_values(0)
-                .max_values(1)
+                .multiple(true)
+                .require_delimiter(true)
                 .possible_values(&["primary", "secondary", "clipboard"])
-                .help("Output to selection (defaults to clipboard)"),
+                .help("Output to selections, separated by commas (defaults to clipboard)"),
+        )
+        .arg(
+            Arg::with_name("tee")
+                .long("tee")
+                .requires("selection")
+                .help("Also print to standard output when saving to a selection"),
+        )
+        .arg(
+            Arg::with_name("max_pastes")
+                .long("max-pastes")
+                .takes_value(true)
+                .value_name("COUNT")
+                .help("Stop serving the selection after it has been pasted COUNT times"),
+        )
+        .arg(
+            Arg::with_name("selection_timeout")
+                .long("selection-timeout")
+                .takes_value(true)
+                .value_name("DURATION")
+                .help("Stop serving the selection after DURATION (e.g. 10m or 1h)"),
+        )
+        .arg(
+            Arg::with_name("swatch_size")
+                .long("swatch-size")
+                .takes_value(true)
+                .value_name("PIXELS")
+                .help("Size of the image/png swatch offered to the selection (defaults to 64)"),
         )
         .arg(
             Arg::with_name("scale")
@@ -42,7 +72,7 @@ pub fn get_cli() -> App<'static, 'static> {
                 .long("scale")
                 .takes_value(true)
                 .value_name("SCALE")
-                .help("Scale of magnification (defaults to 8)"),
+                .help("Scale of magnification (defaults to 8, more on HiDPI screens)"),
         )
         .arg(
             Arg::with_name("preview_size")
@@ -50,6 +80,96 @@ pub fn get_cli() -> App<'static, 'static> {
                 .long("preview-size")
                 .takes_value(true)
                 .value_name("PREVIEW_SIZE")
-                .help("Size of preview, must be odd (defaults to 255)"),
+                .help("Size of preview, must be odd (defaults to 255, more on HiDPI screens)"),
+        )
+        .arg(
+            Arg::with_name("shape")
+                .long("shape")
+                .takes_value(true)
+                .value_name("SHAPE")
+                .possible_values(&["circle", "square", "rounded"])
+                .help("Shape of the preview (defaults to circle)"),
+        )
+        .arg(
+            Arg::with_name("border_width")
+                .long("border-width")
+                .takes_value(true)
+                .value_name("PIXELS")
+                .help("Width of the preview's border (defaults to 1)"),
+        )
+        .arg(
+            Arg::with_name("border_color")
+                .long("border-color")
+                .takes_value(true)
+                .value_name("COLOR")
+                .help("Color of the preview's border as #rrggbb (defaults to contrasting)"),
+        )
+        .arg(
+            Arg::with_name("no_grid")
+                .long("no-grid")
+                .conflicts_with("grid_opacity")
+                .help("Hide the grid between magnified pixels"),
+        )
+        .arg(
+            Arg::with_name("grid_opacity")
+                .long("grid-opacity")
+                .takes_value(true)
+                .value_name("AMOUNT")
+                .help("Visibility of the grid, between 0 and 1 (defaults to 0.2)"),
+        )
+        .arg(
+            Arg::with_name("highlight")
+                .long("highlight")
+                .takes_value(true)
+                .value_name("STYLE")
+                .possible_values(&["box", "crosshair"])
+                .help("How the picked pixel is marked in the preview (defaults to box)"),
+        )
+        .arg(
+            Arg::with_name("delay")
+                .short("d")
+                .long("delay")
+                .takes_value(true)
+                .value_name("DURATION")
+                .help("Wait before starting to pick (e.g. 2 or 500ms)"),
+        )
+        .arg(
+            Arg::with_name("freeze")
+                .short("F")
+                .long("freeze")
+                .help("Capture the screen once and pick from that snapshot"),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .short("t")
+                .long("timeout")
+                .takes_value(true)
+                .value_name("DURATION")
+                .help("Give up if nothing is picked in time (e.g. 30s or 2m)"),
+        )
+        .arg(
+            Arg::with_name("grab_retries")
+                .long("grab-retries")
+                .takes_value(true)
+                .value_name("COUNT")
+                .help("Times to retry if another client has grabbed the pointer (defaults to 5)"),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("Continuously print the color under the pointer")
+                .arg(
+                    Arg::with_name("interval")
+                        .short("i")
+                        .long("interval")
+                        .takes_value(true)
+                        .value_name("DURATION")
+                        .help("Print every DURATION instead of only on changes (e.g. 500ms)"),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .short("j")
+                        .long("json")
+                        .help("Print each color as a JSON object with pointer coordinates"),
+                ),
         )
 }
@@ -1,9 +1,10 @@
-use anyhow::{anyhow, Result};
-use std;
+use anyhow::{anyhow, Error, Result};
+use std::str::FromStr;
 use xcb::xproto;
 use xcb::Connection;
 
 #[derive(Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
 pub struct ARGB {
     pub a: u8,
     pub r: u8,
@@ -74,6 +75,31 @@ impl ARGB {
     }
 }
 
+impl FromStr for ARGB {
+    type Err = Error;
+    // Parses opaque colors written as `#rrggbb` or `#rgb`, the `#` being optional
+    fn from_str(s: &str) -> Result<Self> {
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(anyhow!("Invalid color"));
+        }
+
+        let channel = |i: usize, len: usize| {
+            let value = u8::from_str_radix(&hex[i * len..(i + 1) * len], 16).unwrap();
+            if len == 1 {
+                value * 0x11
+            } else {
+                value
+            }
+        };
+        match hex.len() {
+            3 => Ok(ARGB::new(0xff, channel(0, 1), channel(1, 1), channel(2, 1))),
+            6 => Ok(ARGB::new(0xff, channel(0, 2), channel(1, 2), channel(2, 2))),
+            _ => Err(anyhow!("Invalid color")),
+        }
+    }
+}
+
 impl From<ARGB> for u32 {
     fn from(color: ARGB) -> u32 {
         u32::from(color.a) << 24
@@ -96,17 +122,21 @@ pub fn window_rect(
         y,
         width,
         height,
-        std::u32::MAX,
+        u32::MAX,
     )
     .get_reply()?;
 
-    if reply.depth() != 24 {
+    decode_pixels(reply.depth(), reply.data())
+}
+
+// Converts a ZPixmap image returned by the X server into colors
+pub fn decode_pixels(depth: u8, data: &[u8]) -> Result<Vec<ARGB>> {
+    if depth != 24 {
         // TODO: Figure out what to do with these
         return Err(anyhow!("Unsupported color depth"));
     }
 
-    let data = reply.data();
-    let mut pixels = Vec::with_capacity(data.len());
+    let mut pixels = Vec::with_capacity(data.len() / 4);
     for chunk in data.chunks(4) {
         pixels.push(ARGB::new(0xff, chunk[2], chunk[1], chunk[0]));
     }
@@ -122,3 +152,13 @@ fn test_compaction() {
     assert!(!ARGB::new(0xff, 0xf7, 0xf7, 0xf7).is_compactable());
     assert!(!ARGB::new(0xff, 0xff, 0xf7, 0xff).is_compactable());
 }
+
+#[test]
+fn test_parse_color() {
+    let color: ARGB = "#12abEF".parse().unwrap();
+    assert!(color == ARGB::new(0xff, 0x12, 0xab, 0xef));
+    let color: ARGB = "f0a".parse().unwrap();
+    assert!(color == ARGB::new(0xff, 0xff, 0x00, 0xaa));
+    assert!("#12345".parse::<ARGB>().is_err());
+    assert!("#ggg".parse::<ARGB>().is_err());
+}
@@ -0,0 +1,61 @@
+use xcb::base as xbase;
+use xcb::base::{Connection, GenericEvent};
+use xcb::damage;
+use xcb::xproto;
+
+use crate::capture::Rect;
+use crate::util::has_extension;
+
+/// Reports which parts of the screen changed, using the DAMAGE extension.
+pub struct DamageWatch<'a> {
+    conn: &'a Connection,
+    damage: damage::Damage,
+    notify: u8,
+}
+
+impl<'a> DamageWatch<'a> {
+    /// Starts watching `root`. Returns `None` if the X server doesn't support DAMAGE.
+    pub fn new(conn: &'a Connection, root: xproto::Window) -> Option<Self> {
+        if !has_extension(conn, damage::id()) {
+            return None;
+        }
+        let data = conn.get_extension_data(damage::id())?;
+        let notify = data.first_event() + damage::NOTIFY;
+
+        // the version has to be negotiated before any other request
+        damage::query_version(conn, damage::MAJOR_VERSION, damage::MINOR_VERSION)
+            .get_reply()
+            .ok()?;
+
+        let damage = conn.generate_id();
+        damage::create_checked(conn, damage, root, damage::REPORT_LEVEL_BOUNDING_BOX as u8)
+            .request_check()
+            .ok()?;
+
+        Some(DamageWatch {
+            conn,
+            damage,
+            notify,
+        })
+    }
+
+    /// Returns the changed area if `event` is a damage notification, and starts
+    /// collecting damage anew so that later changes are reported too.
+    pub fn damaged_area(&self, event: &GenericEvent) -> Option<Rect> {
+        if event.response_type() & !0x80 != self.notify {
+            return None;
+        }
+
+        let event: &damage::NotifyEvent = unsafe { xbase::cast_event(event) };
+        damage::subtract(self.conn, self.damage, xbase::NONE, xbase::NONE);
+
+        let area = event.area();
+        Some((area.x(), area.y(), area.width(), area.height()))
+    }
+}
+
+impl<'a> Drop for DamageWatch<'a> {
+    fn drop(&mut self) {
+        damage::destroy(self.conn, self.damage);
+    }
+}
@@ -0,0 +1,83 @@
+use std::env;
+use xcb::base::Connection;
+use xcb::xproto;
+
+// The resolution at which the default sizes look as intended
+const BASE_DPI: f64 = 96.0;
+// The usual cursor size at `BASE_DPI`
+const BASE_CURSOR_SIZE: f64 = 24.0;
+const MM_PER_INCH: f64 = 25.4;
+
+// Reads `Xft.dpi` from X resources, which desktop environments set to match their scaling
+fn parse_xft_dpi(resources: &str) -> Option<f64> {
+    resources.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if name.trim() != "Xft.dpi" {
+            return None;
+        }
+        value.trim().parse().ok().filter(|dpi: &f64| *dpi > 0.0)
+    })
+}
+
+fn xft_dpi(conn: &Connection, screen: &xproto::Screen) -> Option<f64> {
+    let reply = xproto::get_property(
+        conn,
+        false,
+        screen.root(),
+        xproto::ATOM_RESOURCE_MANAGER,
+        xproto::ATOM_STRING,
+        0,
+        u32::MAX,
+    )
+    .get_reply()
+    .ok()?;
+
+    parse_xft_dpi(&String::from_utf8_lossy(reply.value()))
+}
+
+fn cursor_size_factor() -> Option<f64> {
+    let size: f64 = env::var("XCURSOR_SIZE").ok()?.trim().parse().ok()?;
+    if size > 0.0 {
+        Some(size / BASE_CURSOR_SIZE)
+    } else {
+        None
+    }
+}
+
+// The physical size is often made up by the X server, but it's all we have left
+fn physical_dpi(screen: &xproto::Screen) -> Option<f64> {
+    let millimeters = screen.width_in_millimeters();
+    if millimeters == 0 {
+        return None;
+    }
+    Some(f64::from(screen.width_in_pixels()) / (f64::from(millimeters) / MM_PER_INCH))
+}
+
+/// Returns how much larger than usual things should be drawn on this screen,
+/// based on `Xft.dpi`, `$XCURSOR_SIZE` or the physical size of the screen, in
+/// that order. The result is never less than 1.
+pub fn scale_factor(conn: &Connection, screen: &xproto::Screen) -> f64 {
+    let factor = xft_dpi(conn, screen)
+        .map(|dpi| dpi / BASE_DPI)
+        .or_else(cursor_size_factor)
+        .or_else(|| physical_dpi(screen).map(|dpi| dpi / BASE_DPI))
+        .unwrap_or(1.0);
+
+    // round to quarters so that slightly inaccurate sizes don't change anything
+    ((factor * 4.0).round() / 4.0).max(1.0)
+}
+
+/// Scales a default size by `factor`.
+pub fn scaled(value: u32, factor: f64) -> u32 {
+    (f64::from(value) * factor).round() as u32
+}
+
+#[test]
+fn test_parse_xft_dpi() {
+    let resources = "Xcursor.size:\t48\nXft.antialias:\t1\nXft.dpi:\t192\n";
+    assert_eq!(parse_xft_dpi(resources), Some(192.0));
+    assert_eq!(parse_xft_dpi("Xft.dpi: 120.5"), Some(120.5));
+    assert_eq!(parse_xft_dpi("Xft.dpi:\t0\n"), None);
+    assert_eq!(parse_xft_dpi("Xft.hinting:\t1\n"), None);
+    assert_eq!(scaled(255, 2.0), 510);
+}
@@ -1,81 +1,313 @@
 use crate::color::ARGB;
+use crate::font::{draw_text, text_width, GLYPH_HEIGHT};
 use crate::pixel::PixelSquare;
+use crate::style::{Highlight, Shape, Style};
 
-#[inline]
-fn is_inside_circle(x: isize, y: isize, r: isize) -> bool {
-    (x - r).pow(2) + (y - r).pow(2) < r.pow(2)
+// Translucent black (premultiplied), white text stays legible on it whatever the color underneath
+const LABEL_BACKGROUND: u32 = 0xc000_0000;
+const LABEL_PADDING: usize = 3;
+const LABEL_LINE_SPACING: usize = 2;
+
+// Returns how much of the pixel at `(dx, dy)`, relative to the center of the magnifier, is
+// covered by `shape` with the given radius, from 0 to 1. Partially covered pixels give the
+// outline smooth edges.
+fn coverage(shape: Shape, dx: f32, dy: f32, radius: f32) -> f32 {
+    let corner = match shape {
+        Shape::Circle => radius,
+        Shape::Square => 0.0,
+        Shape::RoundedSquare => radius / 4.0,
+    };
+
+    // signed distance from the pixel's center to the outline, negative inside the shape
+    let qx = dx.abs() - (radius - corner);
+    let qy = dy.abs() - (radius - corner);
+    let distance = qx.max(0.0).hypot(qy.max(0.0)) + qx.max(qy).min(0.0) - corner;
+
+    (0.5 - distance).clamp(0.0, 1.0)
+}
+
+// Scales `color` by `amount` and premultiplies it by its alpha
+fn premultiply(color: ARGB, amount: f32) -> [f32; 4] {
+    let alpha = f32::from(color.a) / 255.0 * amount;
+    [
+        255.0 * alpha,
+        f32::from(color.r) * alpha,
+        f32::from(color.g) * alpha,
+        f32::from(color.b) * alpha,
+    ]
+}
+
+// Combines the content and the border of a pixel, each covering part of it, into premultiplied ARGB
+fn compose(content: ARGB, content_coverage: f32, border: ARGB, border_coverage: f32) -> u32 {
+    let content = premultiply(content, content_coverage);
+    let border = premultiply(border, border_coverage);
+
+    let mut pixel = 0;
+    for i in 0..4 {
+        let channel = (content[i] + border[i]).round().min(255.0) as u32;
+        pixel |= channel << (24 - 8 * i);
+    }
+    pixel
 }
 
 #[inline]
-fn border_color(color: ARGB) -> u32 {
+fn border_color(color: ARGB) -> ARGB {
     if color.is_dark() {
-        ARGB::WHITE.into()
+        ARGB::WHITE
     } else {
-        ARGB::BLACK.into()
+        ARGB::BLACK
     }
 }
 
-pub fn draw_magnifying_glass(
-    cursor: &mut PixelSquare<&mut [u32]>,
-    screenshot: &PixelSquare<&[ARGB]>,
-    pixel_size: usize,
-) {
-    assert!(pixel_size % 2 != 0, "pixel_size must be odd");
-    assert!(cursor.width() % 2 != 0, "cursor.width must be odd");
-    assert!(screenshot.width() % 2 != 0, "screenshot.width must be odd");
-
-    let transparent: u32 = ARGB::TRANSPARENT.into();
-
-    let pixel_size = pixel_size as isize;
-    let cursor_width = cursor.width() as isize;
-    let screenshot_width = screenshot.width() as isize;
-
-    let border_width = 1;
-    let border_radius = cursor_width / 2;
-    let content_radius = border_radius - border_width;
-
-    let cursor_center = cursor_width / 2;
-    let cursor_center_pixel = cursor_center - pixel_size / 2;
-    let screenshot_center = screenshot_width / 2;
-    let offset = screenshot_center * pixel_size - cursor_center_pixel;
-
-    for cx in 0..cursor_width {
-        for cy in 0..cursor_width {
-            // screenshot coordinates
-            let sx = ((cx + offset) / pixel_size) as usize;
-            let sy = ((cy + offset) / pixel_size) as usize;
-            let screenshot_color = screenshot[(sx, sy)];
-
-            // set cursor pixel
-            cursor[(cx as usize, cy as usize)] = if is_inside_circle(cx, cy, content_radius) {
+/// Draws the magnified screenshot into the preview. Implement this to add new
+/// kinds of magnifiers.
+pub trait Renderer {
+    /// Draws `screenshot` into `cursor`, each screenshot pixel covering
+    /// `pixel_size` by `pixel_size` cursor pixels. All sizes are odd so that
+    /// both have a center pixel.
+    fn render(
+        &self,
+        cursor: &mut PixelSquare<&mut [u32]>,
+        screenshot: &PixelSquare<&[ARGB]>,
+        pixel_size: usize,
+    );
+}
+
+/// The default magnifier, whose look is configured by a `Style`.
+pub struct MagnifyingGlass {
+    style: Style,
+}
+
+impl MagnifyingGlass {
+    pub fn new(style: Style) -> Self {
+        MagnifyingGlass { style }
+    }
+}
+
+impl Renderer for MagnifyingGlass {
+    fn render(
+        &self,
+        cursor: &mut PixelSquare<&mut [u32]>,
+        screenshot: &PixelSquare<&[ARGB]>,
+        pixel_size: usize,
+    ) {
+        assert!(pixel_size % 2 != 0, "pixel_size must be odd");
+        assert!(cursor.width() % 2 != 0, "cursor.width must be odd");
+        assert!(screenshot.width() % 2 != 0, "screenshot.width must be odd");
+
+        let style = &self.style;
+
+        let pixel_size = pixel_size as isize;
+        let cursor_width = cursor.width() as isize;
+        let screenshot_width = screenshot.width() as isize;
+
+        // the outline lies on the edge of the outermost pixels
+        let border_radius = (cursor_width / 2) as f32 + 0.5;
+        let content_radius = border_radius - style.border_width as f32;
+
+        let cursor_center = cursor_width / 2;
+        let cursor_center_pixel = cursor_center - pixel_size / 2;
+        let screenshot_center = screenshot_width / 2;
+        let offset = screenshot_center * pixel_size - cursor_center_pixel;
+
+        for cx in 0..cursor_width {
+            for cy in 0..cursor_width {
+                // screenshot coordinates
+                let sx = ((cx + offset) / pixel_size) as usize;
+                let sy = ((cy + offset) / pixel_size) as usize;
+                let screenshot_color = screenshot[(sx, sy)];
+
+                let (dx, dy) = (cx - cursor_center, cy - cursor_center);
+                let outer = coverage(style.shape, dx as f32, dy as f32, border_radius);
+                let inner = coverage(style.shape, dx as f32, dy as f32, content_radius).min(outer);
+
+                let is_center_x =
+                    cx >= cursor_center_pixel && cx <= cursor_center_pixel + pixel_size;
+                let is_center_y =
+                    cy >= cursor_center_pixel && cy <= cursor_center_pixel + pixel_size;
                 let is_grid_line =
                     (cx + offset) % pixel_size == 0 || (cy + offset) % pixel_size == 0;
 
-                if is_grid_line {
-                    let is_center_x =
-                        cx >= cursor_center_pixel && cx <= cursor_center_pixel + pixel_size;
-                    let is_center_y =
-                        cy >= cursor_center_pixel && cy <= cursor_center_pixel + pixel_size;
+                let is_highlight = match style.highlight {
+                    // the grid lines around the center pixel
+                    Highlight::Box => is_grid_line && is_center_x && is_center_y,
+                    // lines through the center pixel, leaving the pixel itself visible
+                    Highlight::Crosshair => (dx == 0 && !is_center_y) || (dy == 0 && !is_center_x),
+                };
 
-                    // center pixel's border color
-                    if is_center_x && is_center_y {
-                        border_color(screenshot_color)
+                let content = if is_highlight {
+                    border_color(screenshot_color)
+                } else if style.grid && is_grid_line {
+                    if screenshot_color.is_dark() {
+                        screenshot_color.lighten(style.grid_opacity)
                     } else {
-                        // grid color
-                        if screenshot_color.is_dark() {
-                            screenshot_color.lighten(0.2).into()
-                        } else {
-                            screenshot_color.darken(0.2).into()
-                        }
+                        screenshot_color.darken(style.grid_opacity)
                     }
                 } else {
-                    screenshot_color.into()
-                }
-            } else if is_inside_circle(cx + border_width, cy + border_width, border_radius) {
-                border_color(screenshot_color)
-            } else {
-                transparent
-            };
+                    screenshot_color
+                };
+                let border = style
+                    .border_color
+                    .unwrap_or_else(|| border_color(screenshot_color));
+
+                // set cursor pixel
+                cursor[(cx as usize, cy as usize)] = compose(content, inner, border, outer - inner);
+            }
+        }
+    }
+}
+
+/// Draws the magnifier for `screenshot` with `renderer`, then `label` on top.
+pub fn draw_magnifier(
+    cursor: &mut PixelSquare<&mut [u32]>,
+    screenshot: &PixelSquare<&[ARGB]>,
+    renderer: &dyn Renderer,
+    label: &[String],
+) {
+    // find out how large our pixels should be in the picker - this must be an odd number (so
+    // there's a center pixel) and it must be slightly higher than the ratio between the
+    // cursor and the screenshot (to account for integer division so no out of bounds accesses
+    // occur when upscaling the image in `render`)
+    let mut pixel_size = cursor.width() / screenshot.width();
+    if pixel_size % 2 == 0 {
+        pixel_size += 1;
+    } else {
+        pixel_size += 2;
+    }
+
+    renderer.render(cursor, screenshot, pixel_size);
+    draw_label(cursor, label);
+}
+
+// Composites the premultiplied `src` over `dst`
+fn blend_over(src: u32, dst: u32) -> u32 {
+    let alpha = src >> 24;
+    let mut out = 0;
+    for shift in [0, 8, 16, 24] {
+        let s = (src >> shift) & 0xff;
+        let d = (dst >> shift) & 0xff;
+        out |= (s + d * (0xff - alpha) / 0xff) << shift;
+    }
+    out
+}
+
+/// Draws `lines` of text centered in the lower half of the magnifier, on a
+/// translucent dark background that keeps the text legible on any color. Lines
+/// that don't fit inside the magnifier are left out, starting from the last.
+pub fn draw_label(cursor: &mut PixelSquare<&mut [u32]>, lines: &[String]) {
+    let width = cursor.width();
+    let radius = (width / 2) as isize;
+
+    let mut count = lines.len();
+    let (left, top, right, bottom) = loop {
+        if count == 0 {
+            return;
+        }
+
+        let text_width = lines[..count]
+            .iter()
+            .map(|line| text_width(line))
+            .max()
+            .unwrap_or(0);
+        let box_width = text_width + 2 * LABEL_PADDING;
+        let box_height =
+            count * GLYPH_HEIGHT + (count - 1) * LABEL_LINE_SPACING + 2 * LABEL_PADDING;
+
+        let left = (width / 2).saturating_sub(box_width / 2);
+        let top = width / 2 + width / 6;
+        let (right, bottom) = (left + box_width, top + box_height);
+        let fits = [(left, top), (right, top), (left, bottom), (right, bottom)]
+            .iter()
+            .all(|&(x, y)| {
+                // the circle fits inside every shape
+                let (dx, dy) = ((x as isize - radius) as f32, (y as isize - radius) as f32);
+                coverage(Shape::Circle, dx, dy, radius as f32) >= 1.0
+            });
+        if fits {
+            break (left, top, right, bottom);
+        }
+        count -= 1;
+    };
+
+    for y in top..bottom {
+        for x in left..right {
+            let pixel = &mut cursor[y * width + x];
+            *pixel = blend_over(LABEL_BACKGROUND, *pixel);
         }
     }
+
+    for (i, line) in lines[..count].iter().enumerate() {
+        let x = left + LABEL_PADDING + (right - left - 2 * LABEL_PADDING - text_width(line)) / 2;
+        let y = top + LABEL_PADDING + i * (GLYPH_HEIGHT + LABEL_LINE_SPACING);
+        draw_text(cursor, (x, y), line, ARGB::WHITE.into());
+    }
+}
+
+#[test]
+fn test_draw_label() {
+    let opaque: u32 = ARGB::new(0xff, 0x80, 0x80, 0x80).into();
+    assert_eq!(blend_over(LABEL_BACKGROUND, opaque), 0xff1f_1f1f);
+    assert_eq!(blend_over(LABEL_BACKGROUND, 0), LABEL_BACKGROUND);
+
+    let lines = ["#123456".to_owned(), "10, 20".to_owned()];
+    let mut data = vec![opaque; 101 * 101];
+    draw_label(&mut PixelSquare::new(&mut data[..], 101), &lines);
+    assert!(data.contains(&u32::from(ARGB::WHITE)));
+    assert_eq!(data[50 * 101 + 50], opaque);
+
+    // nothing fits into a tiny magnifier
+    let mut data = vec![opaque; 15 * 15];
+    draw_label(&mut PixelSquare::new(&mut data[..], 15), &lines);
+    assert!(data.iter().all(|&pixel| pixel == opaque));
+}
+
+#[test]
+fn test_render_styles() {
+    let red = ARGB::new(0xff, 0xff, 0, 0);
+    let screenshot = [red; 3 * 3];
+    let screenshot = PixelSquare::new(&screenshot[..], 3);
+
+    let render = |style: Style| {
+        let mut data = vec![0; 15 * 15];
+        MagnifyingGlass::new(style).render(
+            &mut PixelSquare::new(&mut data[..], 15),
+            &screenshot,
+            5,
+        );
+        data
+    };
+
+    let circle = render(Style::default());
+    assert_eq!(circle[0], u32::from(ARGB::TRANSPARENT));
+    assert_eq!(circle[7 * 15 + 7], u32::from(red));
+
+    let green = ARGB::new(0xff, 0, 0xff, 0);
+    let square = render(Style {
+        shape: Shape::Square,
+        border_color: Some(green),
+        grid: false,
+        highlight: Highlight::Crosshair,
+        ..Style::default()
+    });
+    assert_eq!(square[0], u32::from(green));
+    assert_eq!(square[7 * 15 + 7], u32::from(red));
+    // without a grid, only the crosshair is drawn over the magnified pixels
+    assert_eq!(square[2 * 15 + 2], u32::from(red));
+    assert_eq!(square[2 * 15 + 7], u32::from(ARGB::WHITE));
+}
+
+#[test]
+fn test_coverage() {
+    for &shape in &[Shape::Circle, Shape::Square, Shape::RoundedSquare] {
+        assert_eq!(coverage(shape, 0.0, 0.0, 10.5), 1.0);
+        assert_eq!(coverage(shape, 12.0, 12.0, 10.5), 0.0);
+        // the edge of a square is aligned to the pixels
+        assert_eq!(coverage(shape, 10.0, 0.0, 10.5), 1.0);
+    }
+    let edge = coverage(Shape::Circle, 8.0, 7.0, 10.5);
+    assert!(edge > 0.0 && edge < 1.0);
+
+    // partially covered pixels are premultiplied
+    let pixel = compose(ARGB::WHITE, 0.0, ARGB::new(0xff, 0xff, 0x80, 0), 0.5);
+    assert_eq!(pixel, 0x8080_4000);
 }
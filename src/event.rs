@@ -0,0 +1,57 @@
+use anyhow::Result;
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::signal::Signal;
+use std::os::unix::io::AsRawFd;
+use std::time::Instant;
+use xcb::base::{Connection, GenericEvent};
+
+use crate::signal;
+
+pub enum Wait {
+    Event(GenericEvent),
+    TimedOut,
+    Interrupted(Signal),
+    Closed,
+}
+
+// Like `Connection::wait_for_event`, but gives up once `deadline` has passed or
+// when a signal caught by `signal::install` arrives
+pub fn wait_for_event(conn: &Connection, deadline: Option<Instant>) -> Result<Wait> {
+    loop {
+        if let Some(signal) = signal::caught() {
+            return Ok(Wait::Interrupted(signal));
+        }
+        if let Some(event) = conn.poll_for_event() {
+            return Ok(Wait::Event(event));
+        }
+        if conn.has_error().is_err() {
+            return Ok(Wait::Closed);
+        }
+
+        let timeout = match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Ok(Wait::TimedOut);
+                }
+                // round up so we don't spin when less than a millisecond is left
+                let remaining = deadline - now;
+                ((remaining.as_micros() + 999) / 1000).min(i32::MAX as u128) as i32
+            }
+            None => -1,
+        };
+
+        // unlike `wait_for_event`, polling doesn't flush pending requests for us
+        conn.flush();
+
+        let mut fds = vec![PollFd::new(conn.as_raw_fd(), PollFlags::POLLIN)];
+        if let Some(fd) = signal::fd() {
+            fds.push(PollFd::new(fd, PollFlags::POLLIN));
+        }
+        match poll(&mut fds, timeout) {
+            Ok(_) | Err(nix::Error::Sys(Errno::EINTR)) => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
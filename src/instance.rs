@@ -0,0 +1,67 @@
+use anyhow::Result;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::{getpid, Pid};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// Records the running selection daemon in `$XDG_RUNTIME_DIR` so that a newer
+/// one can ask it to exit. There is one pidfile per display.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+fn pidfile_path() -> Option<PathBuf> {
+    let runtime_dir = env::var_os("XDG_RUNTIME_DIR")?;
+    let display: String = env::var("DISPLAY")
+        .unwrap_or_default()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    Some(PathBuf::from(runtime_dir).join(format!("xcolor-{}.pid", display)))
+}
+
+// Makes sure the pid wasn't reused by some other program since it was recorded
+fn is_xcolor(pid: Pid) -> bool {
+    fs::read_to_string(format!("/proc/{}/comm", pid))
+        .map(|comm| comm.trim() == env!("CARGO_PKG_NAME"))
+        .unwrap_or(false)
+}
+
+impl PidFile {
+    /// Sends SIGTERM to the previously recorded daemon, if it's still running,
+    /// and records the current process instead. Returns `None` if
+    /// `$XDG_RUNTIME_DIR` isn't set.
+    pub fn replace() -> Result<Option<PidFile>> {
+        let path = match pidfile_path() {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+
+        let previous = fs::read_to_string(&path)
+            .ok()
+            .and_then(|pid| pid.trim().parse().ok())
+            .map(Pid::from_raw);
+        if let Some(pid) = previous {
+            if pid != getpid() && is_xcolor(pid) {
+                // the daemon releases its selections and exits when receiving SIGTERM
+                let _ = kill(pid, Signal::SIGTERM);
+            }
+        }
+
+        fs::write(&path, format!("{}\n", getpid()))?;
+        Ok(Some(PidFile { path }))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // a newer daemon may have replaced the pidfile already
+        let ours = fs::read_to_string(&self.path)
+            .map(|pid| pid.trim() == getpid().to_string())
+            .unwrap_or(false);
+        if ours {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
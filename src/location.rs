@@ -1,226 +1,379 @@
 use anyhow::{anyhow, Result};
-use x11::xcursor::{XcursorImageCreate, XcursorImageDestroy, XcursorImageLoadCursor};
+use nix::sys::signal::Signal;
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
 use xcb::base as xbase;
 use xcb::base::Connection;
 use xcb::xproto;
 
-use crate::color::{self, ARGB};
-use crate::draw::draw_magnifying_glass;
+use crate::capture::{contains, intersection, intersects, Capture, Rect};
+use crate::color::ARGB;
+use crate::damage::DamageWatch;
+use crate::draw::{draw_magnifier, Renderer};
+use crate::event::{wait_for_event, Wait};
+use crate::format::FormatColor;
+use crate::monitor::{monitor_at, monitors};
 use crate::pixel::PixelSquare;
+use crate::preview::{create_preview, Preview};
+use crate::signal;
 use crate::util::EnsureOdd;
 
 // Left mouse button
 const SELECTION_BUTTON: xproto::Button = 1;
-const GRAB_MASK: u16 = (xproto::EVENT_MASK_BUTTON_PRESS | xproto::EVENT_MASK_POINTER_MOTION) as u16;
-
-// Exclusively grabs the pointer so we get all its events
-fn grab_pointer(conn: &Connection, root: u32, cursor: u32) -> Result<()> {
-    let reply = xproto::grab_pointer(
-        conn,
-        false,
-        root,
-        GRAB_MASK,
-        xproto::GRAB_MODE_ASYNC as u8,
-        xproto::GRAB_MODE_ASYNC as u8,
-        xbase::NONE,
-        cursor,
-        xbase::CURRENT_TIME,
-    )
-    .get_reply()?;
-
-    if reply.status() != xproto::GRAB_STATUS_SUCCESS as u8 {
-        return Err(anyhow!("Could not grab pointer"));
+pub const GRAB_MASK: u16 =
+    (xproto::EVENT_MASK_BUTTON_PRESS | xproto::EVENT_MASK_POINTER_MOTION) as u16;
+
+// Delay before the first retry when another client holds a grab, doubled after each attempt
+const GRAB_RETRY_DELAY: Duration = Duration::from_millis(20);
+const GRAB_RETRY_MAX_DELAY: Duration = Duration::from_millis(500);
+
+// Caps how often the magnifier is redrawn while the pointer moves (about 60 times a second)
+const MIN_REDRAW_INTERVAL: Duration = Duration::from_millis(16);
+
+fn grab_status_name(status: u8) -> &'static str {
+    match u32::from(status) {
+        xproto::GRAB_STATUS_ALREADY_GRABBED => "already grabbed by another client",
+        xproto::GRAB_STATUS_INVALID_TIME => "invalid time",
+        xproto::GRAB_STATUS_NOT_VIEWABLE => "window not viewable",
+        xproto::GRAB_STATUS_FROZEN => "frozen by another client",
+        _ => "unknown status",
     }
-
-    Ok(())
 }
 
-// Updates the cursor for an _already grabbed pointer_
-fn update_cursor(conn: &Connection, cursor: u32) -> Result<()> {
-    xproto::change_active_pointer_grab_checked(conn, cursor, xbase::CURRENT_TIME, GRAB_MASK)
-        .request_check()?;
-
-    Ok(())
-}
+// Exclusively grabs the pointer so we get all its events. If another client
+// holds a grab (e.g. a hotkey daemon or a menu that is still closing), retries
+// up to `retries` times with exponential backoff.
+fn grab_pointer(conn: &Connection, root: u32, cursor: u32, retries: u32) -> Result<()> {
+    let mut delay = GRAB_RETRY_DELAY;
+    let mut attempt = 0;
+
+    loop {
+        let reply = xproto::grab_pointer(
+            conn,
+            false,
+            root,
+            GRAB_MASK,
+            xproto::GRAB_MODE_ASYNC as u8,
+            xproto::GRAB_MODE_ASYNC as u8,
+            xbase::NONE,
+            cursor,
+            xbase::CURRENT_TIME,
+        )
+        .get_reply()?;
+
+        let status = reply.status();
+        if status == xproto::GRAB_STATUS_SUCCESS as u8 {
+            return Ok(());
+        }
 
-// Creates a new `XcursorImage`, draws the picker into it and loads it, returning the id for a `Cursor`
-fn create_new_xcursor(
-    conn: &Connection,
-    screenshot_pixels: &PixelSquare<&[ARGB]>,
-    preview_width: u32,
-) -> Result<u32> {
-    Ok(unsafe {
-        let mut cursor_image = XcursorImageCreate(preview_width as i32, preview_width as i32);
-
-        // set the "hot spot" - this is where the pointer actually is inside the image
-        (*cursor_image).xhot = preview_width / 2;
-        (*cursor_image).yhot = preview_width / 2;
-
-        // get pixel data as a mutable Rust slice
-        let mut cursor_pixels =
-            PixelSquare::from_raw_parts((*cursor_image).pixels, preview_width as usize);
-
-        // find out how large our pixels should be in the picker - this must be an odd number (so
-        // there's a center pixel) and it must be slightly higher than the ratio between the
-        // cursor and the screenshot (to account for integer division so no out of bounds accesses
-        // occur when upscaling the image in `draw_magnifying_glass`)
-        let mut pixel_size = cursor_pixels.width() / screenshot_pixels.width();
-        if pixel_size % 2 == 0 {
-            pixel_size += 1;
-        } else {
-            pixel_size += 2;
+        let retryable = status == xproto::GRAB_STATUS_ALREADY_GRABBED as u8
+            || status == xproto::GRAB_STATUS_FROZEN as u8;
+        if !retryable || attempt >= retries {
+            return Err(anyhow!(
+                "Could not grab pointer ({})",
+                grab_status_name(status)
+            ));
         }
 
-        // draw our custom image
-        draw_magnifying_glass(&mut cursor_pixels, screenshot_pixels, pixel_size);
+        thread::sleep(delay);
+        delay = (delay * 2).min(GRAB_RETRY_MAX_DELAY);
+        attempt += 1;
+    }
+}
 
-        // convert our XcursorImage into a cursor
-        let cursor_id = XcursorImageLoadCursor(conn.get_raw_dpy(), cursor_image) as u32;
+// Returns the current position of the pointer relative to `root`
+pub fn query_pointer(conn: &Connection, root: xproto::Window) -> Result<(i16, i16)> {
+    let pointer = xproto::query_pointer(conn, root).get_reply()?;
+    Ok((pointer.root_x(), pointer.root_y()))
+}
 
-        // free the XcursorImage
-        XcursorImageDestroy(cursor_image);
+// Returns the rect shown in the magnifier when the pointer is at `point`, which may extend past
+// the edges of the monitor
+fn preview_rect((x, y): (i16, i16), preview_width: u32, scale: u32) -> Rect {
+    let size = ((preview_width / scale) as isize).ensure_odd();
+    let x = (x as isize) - (size / 2);
+    let y = (y as isize) - (size / 2);
+    (x as i16, y as i16, size as u16, size as u16)
+}
 
-        cursor_id
-    } as u32)
+// Returns the color shown for pixels that aren't on the monitor under the pointer, a checkerboard
+// that stays in place as the pointer moves
+fn out_of_bounds_color(x: i32, y: i32) -> ARGB {
+    if (x + y).rem_euclid(2) == 0 {
+        ARGB::new(0xff, 0x40, 0x40, 0x40)
+    } else {
+        ARGB::new(0xff, 0x70, 0x70, 0x70)
+    }
 }
 
-// NOTE: this works for multi-monitor configurations since it seems that X fills in the blank
-// space with empty pixels when calling XGetImage with a rect that crosses the boundaries of two differently
-// sized or misaligned screens
+// Captures the pixels shown in the magnifier when the pointer is at `point`. Only the monitor under
+// the pointer is captured: pixels past its edges, whether they are off the screen, in the gaps
+// between differently sized monitors or on a neighbouring monitor, are marked as out of bounds.
 fn get_window_rect_around_pointer(
     conn: &Connection,
-    screen: &xproto::Screen,
-    (pointer_x, pointer_y): (i16, i16),
+    monitors: &[Rect],
+    capture: &mut dyn Capture,
+    point: (i16, i16),
     preview_width: u32,
     scale: u32,
 ) -> Result<(u16, Vec<ARGB>)> {
-    let root = screen.root();
-    let root_width = screen.width_in_pixels() as isize;
-    let root_height = screen.height_in_pixels() as isize;
-
-    let size = ((preview_width / scale) as isize).ensure_odd();
+    let preview = preview_rect(point, preview_width, scale);
+    let size = preview.2;
 
-    // the top left coordinates of the rect: make sure they don't go offscreen
-    let mut x = (pointer_x as isize) - (size / 2);
-    let mut y = (pointer_y as isize) - (size / 2);
-    let x_offset = if x < 0 { -x } else { 0 };
-    let y_offset = if y < 0 { -y } else { 0 };
-    x += x_offset;
-    y += y_offset;
-
-    // the size of the rect: make sure they don't extend past the screen
-    let size_x = if x + size > (root_width) {
-        (root_width) - x
-    } else {
-        size - x_offset
-    };
-    let size_y = if y + size > (root_height) {
-        (root_height) - y
-    } else {
-        size - y_offset
+    let visible = intersection(preview, monitor_at(monitors, point));
+    let screenshot = match visible {
+        Some(visible) => capture.capture(conn, visible)?,
+        None => Vec::new(),
     };
 
-    // grab a screenshot of the rect
-    let rect = (x as i16, y as i16, size_x as u16, size_y as u16);
-    let screenshot_rect = color::window_rect(conn, root, rect)?;
+    // the entire portion of the screenshot is on the monitor
+    if visible == Some(preview) {
+        return Ok((size, screenshot));
+    }
 
-    // the entire portion of the screenshot is on screen
-    if size_x == size && size_y == size {
-        return Ok((size as u16, screenshot_rect));
+    let mut pixels = Vec::with_capacity(usize::from(size) * usize::from(size));
+    for row in 0..i32::from(size) {
+        for column in 0..i32::from(size) {
+            let x = i32::from(preview.0) + column;
+            let y = i32::from(preview.1) + row;
+
+            let pixel = match visible {
+                Some((vx, vy, width, height))
+                    if x >= vx.into()
+                        && y >= vy.into()
+                        && x < i32::from(vx) + i32::from(width)
+                        && y < i32::from(vy) + i32::from(height) =>
+                {
+                    let index = (y - i32::from(vy)) * i32::from(width) + (x - i32::from(vx));
+                    screenshot[index as usize]
+                }
+                _ => out_of_bounds_color(x, y),
+            };
+            pixels.push(pixel);
+        }
     }
 
-    // NOTE: XCB APIs fail when requesting a region outside the screen, so clamp the rect to the screen and
-    // fill the clamped pixels with empty data
-    let mut pixels = vec![ARGB::TRANSPARENT; (size * size) as usize];
-    for x in 0..size_x {
-        for y in 0..size_y {
-            let screenshot_idx = (y * size_x) + x;
-            let pixels_idx = (y + y_offset) * size + (x + x_offset);
+    Ok((size, pixels))
+}
 
-            pixels[pixels_idx as usize] = screenshot_rect[screenshot_idx as usize];
-        }
+// Returned when nothing was picked before the timeout passed
+#[derive(Debug)]
+pub struct PickTimeout;
+
+impl fmt::Display for PickTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Timed out waiting for a color to be picked")
     }
+}
+
+impl std::error::Error for PickTimeout {}
 
-    Ok((size as u16, pixels))
+// Returned when SIGINT or SIGTERM stopped picking
+#[derive(Debug)]
+pub struct PickInterrupted(pub Signal);
+
+impl fmt::Display for PickInterrupted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Interrupted by {} while picking a color", self.0)
+    }
 }
 
-fn create_new_cursor(
-    conn: &Connection,
-    screen: &xproto::Screen,
+impl std::error::Error for PickInterrupted {}
+
+// The magnifier shown while picking
+struct Magnifier<'a> {
+    conn: &'a Connection,
+    // monitor geometry, queried once when picking starts
+    monitors: Vec<Rect>,
+    capture: &'a mut dyn Capture,
+    preview: Box<dyn Preview>,
+    formatter: &'a dyn FormatColor,
+    renderer: &'a dyn Renderer,
+    // pointer position the magnifier was last drawn for
+    point: (i16, i16),
     preview_width: u32,
     scale: u32,
-    point: Option<(i16, i16)>,
-) -> Result<u32> {
-    let point = match point {
-        Some(point) => point,
-        None => {
-            let root = screen.root();
-            let pointer = xproto::query_pointer(conn, root).get_reply()?;
-            (pointer.root_x(), pointer.root_y())
+}
+
+impl<'a> Magnifier<'a> {
+    // Shows the magnifier around `point`
+    fn redraw(&mut self, point: (i16, i16)) -> Result<()> {
+        let (w, p) = get_window_rect_around_pointer(
+            self.conn,
+            &self.monitors,
+            self.capture,
+            point,
+            self.preview_width,
+            self.scale,
+        )?;
+        let pixels = PixelSquare::new(&p[..], w.into());
+
+        let center = usize::from(w / 2);
+        let color = pixels[(center, center)];
+        let label = [
+            self.formatter.format(color),
+            format!("{}, {}", point.0, point.1),
+        ];
+
+        let renderer = self.renderer;
+        let draw = |cursor: &mut PixelSquare<&mut [u32]>| {
+            draw_magnifier(cursor, &pixels, renderer, &label);
+        };
+        self.preview.show(self.conn, &draw, point)?;
+        self.point = point;
+
+        Ok(())
+    }
+}
+
+fn pick_location(
+    magnifier: &mut Magnifier,
+    damage: Option<&DamageWatch>,
+    deadline: Option<Instant>,
+) -> Result<Option<ARGB>> {
+    let conn = magnifier.conn;
+
+    // latest pointer position the magnifier hasn't been drawn for yet
+    let mut pending = None;
+    let mut next_redraw = Instant::now();
+
+    loop {
+        // handle everything that's queued before drawing, so we only draw for
+        // the latest position instead of trailing behind the pointer
+        let event = match conn.poll_for_event() {
+            Some(event) => event,
+            None => {
+                let now = Instant::now();
+                if let Some(point) = pending {
+                    if now >= next_redraw {
+                        magnifier.redraw(point)?;
+                        pending = None;
+                        next_redraw = now + MIN_REDRAW_INTERVAL;
+                        continue;
+                    }
+                }
+
+                let wake = match (pending, deadline) {
+                    (Some(_), Some(deadline)) => Some(deadline.min(next_redraw)),
+                    (Some(_), None) => Some(next_redraw),
+                    (None, deadline) => deadline,
+                };
+                match wait_for_event(conn, wake)? {
+                    Wait::Event(event) => event,
+                    Wait::TimedOut
+                        if deadline.map_or(false, |deadline| Instant::now() >= deadline) =>
+                    {
+                        return Err(PickTimeout.into())
+                    }
+                    Wait::TimedOut => continue,
+                    Wait::Interrupted(signal) => return Err(PickInterrupted(signal).into()),
+                    Wait::Closed => return Ok(None),
+                }
+            }
+        };
+
+        match event.response_type() {
+            xproto::BUTTON_PRESS => {
+                let event: &xproto::ButtonPressEvent = unsafe { xbase::cast_event(&event) };
+                if event.detail() == SELECTION_BUTTON {
+                    let rect = (event.root_x(), event.root_y(), 1, 1);
+                    let pixels = magnifier.capture.capture(conn, rect)?;
+
+                    return Ok(Some(pixels[0]));
+                }
+            }
+            xproto::MOTION_NOTIFY => {
+                let event: &xproto::MotionNotifyEvent = unsafe { xbase::cast_event(&event) };
+                pending = Some((event.root_x(), event.root_y()));
+            }
+            _ => {
+                // drawing the magnifier window damages the screen too, that's not a change to show
+                let own = magnifier.preview.area();
+                let area = damage
+                    .and_then(|damage| damage.damaged_area(&event))
+                    .filter(|&area| !own.map_or(false, |own| contains(own, area)));
+                if let Some(area) = area {
+                    magnifier.capture.invalidate(area);
+
+                    // the content under a still pointer changed, e.g. a video is playing
+                    let shown =
+                        preview_rect(magnifier.point, magnifier.preview_width, magnifier.scale);
+                    if pending.is_none() && intersects(shown, area) {
+                        pending = Some(magnifier.point);
+                    }
+                }
+            }
         }
-    };
+    }
+}
 
-    let (w, p) = get_window_rect_around_pointer(conn, screen, point, preview_width, scale)?;
-    let pixels = PixelSquare::new(&p[..], w.into());
-    create_new_xcursor(conn, &pixels, preview_width)
+/// Options for picking a color with `wait_for_location`.
+pub struct PickOptions<'a> {
+    pub preview_width: u32,
+    pub scale: u32,
+    pub timeout: Option<Duration>,
+    pub grab_retries: u32,
+    /// Formats the color shown in the magnifier's label
+    pub formatter: &'a dyn FormatColor,
+    pub renderer: &'a dyn Renderer,
 }
 
 pub fn wait_for_location(
     conn: &Connection,
     screen: &xproto::Screen,
-    preview_width: u32,
-    scale: u32,
+    capture: &mut dyn Capture,
+    damage: Option<&DamageWatch>,
+    options: &PickOptions,
 ) -> Result<Option<ARGB>> {
+    let PickOptions {
+        preview_width,
+        scale,
+        timeout,
+        grab_retries,
+        formatter,
+        renderer,
+    } = *options;
     let root = screen.root();
     let preview_width = preview_width.ensure_odd();
 
-    // grab the cursor to listen to all of its events
-    let mut cursor = create_new_cursor(conn, screen, preview_width, scale, None)?;
-    grab_pointer(conn, root, cursor)?;
-
-    let result = loop {
-        let event = conn.wait_for_event();
-        if let Some(event) = event {
-            match event.response_type() {
-                xproto::BUTTON_PRESS => {
-                    let event: &xproto::ButtonPressEvent = unsafe { xbase::cast_event(&event) };
-                    match event.detail() {
-                        SELECTION_BUTTON => {
-                            let pixels = color::window_rect(
-                                conn,
-                                root,
-                                (event.root_x(), event.root_y(), 1, 1),
-                            )?;
-
-                            break Some(pixels[0]);
-                        }
-                        _ => {}
-                    }
-                }
-                xproto::MOTION_NOTIFY => {
-                    let event: &xproto::MotionNotifyEvent = unsafe { xbase::cast_event(&event) };
-                    let new_cursor = create_new_cursor(
-                        conn,
-                        screen,
-                        preview_width,
-                        scale,
-                        Some((event.root_x(), event.root_y())),
-                    )?;
-                    update_cursor(conn, new_cursor)?;
-
-                    xproto::free_cursor(conn, cursor);
-                    cursor = new_cursor;
-                }
-                _ => {}
-            }
-        } else {
-            break None;
-        }
+    // SIGINT and SIGTERM stop picking instead of killing us with the pointer grabbed
+    signal::install()?;
+
+    // the magnifier window must stay clear of everything that's captured around the pointer
+    let monitors = monitors(conn, screen);
+    let clearance = (preview_width / scale) as i32 / 2 + capture.margin();
+    let preview = create_preview(conn, screen, &monitors, preview_width, clearance)?;
+
+    let mut magnifier = Magnifier {
+        conn,
+        monitors,
+        capture,
+        preview,
+        formatter,
+        renderer,
+        point: query_pointer(conn, root)?,
+        preview_width,
+        scale,
     };
 
+    // grab the cursor to listen to all of its events
+    let grabbed = magnifier
+        .redraw(magnifier.point)
+        .and_then(|_| grab_pointer(conn, root, magnifier.preview.cursor(), grab_retries));
+    if let Err(err) = grabbed {
+        magnifier.preview.destroy(conn);
+        conn.flush();
+        return Err(err);
+    }
+
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let result = pick_location(&mut magnifier, damage, deadline);
+
+    // release the grab whatever happened, a leftover grab locks up the whole session
     xproto::ungrab_pointer(conn, xbase::CURRENT_TIME);
-    xproto::free_cursor(conn, cursor);
+    magnifier.preview.destroy(conn);
     conn.flush();
 
-    Ok(result)
+    result
 }
@@ -1,25 +1,53 @@
 mod atoms;
+mod capture;
 mod cli;
 mod color;
+mod damage;
+mod dpi;
 mod draw;
+mod event;
+mod font;
 mod format;
+mod instance;
 mod location;
+mod monitor;
 mod pixel;
+mod png;
+mod preview;
 mod selection;
+mod signal;
+mod style;
 mod util;
+mod watch;
 
 use anyhow::{anyhow, Result};
 use clap::{value_t, ArgMatches, ErrorKind};
 use nix::unistd::ForkResult;
+use std::io::{self, Write};
+use std::thread;
+use std::time::Duration;
 use xcb::base::Connection;
 
+use crate::capture::{Capture, FrozenCapture, LiveCapture, ShmCapture, TileCapture};
 use crate::cli::get_cli;
+use crate::damage::DamageWatch;
+use crate::draw::MagnifyingGlass;
 use crate::format::{Format, FormatColor, FormatString};
-use crate::location::wait_for_location;
-use crate::selection::{into_daemon, set_selection, Selection};
+use crate::instance::PidFile;
+use crate::location::{wait_for_location, PickInterrupted, PickOptions, PickTimeout};
+use crate::selection::{into_daemon, set_selection, Selection, SelectionOptions};
+use crate::style::Style;
+use crate::util::parse_duration;
+use crate::watch::watch;
 
 const DEFAULT_PREVIEW_SIZE: u32 = 256 - 1;
 const DEFAULT_SCALE: u32 = 8;
+const DEFAULT_GRAB_RETRIES: u32 = 5;
+const DEFAULT_SWATCH_SIZE: u32 = 64;
+const MAX_SWATCH_SIZE: u32 = 4096;
+
+// Exit status used when nothing was picked before `--timeout` passed
+const EXIT_TIMEOUT: i32 = 2;
 
 fn run(args: &ArgMatches) -> Result<()> {
     fn error(message: &str) -> ! {
@@ -42,21 +70,121 @@ fn run(args: &ArgMatches) -> Result<()> {
         &simple_format
     };
 
-    let scale = value_t!(args.value_of("scale"), u32).unwrap_or_else(|e| match e.kind {
-        ErrorKind::ArgumentNotFound => DEFAULT_SCALE,
-        _ => error(&format!("{}", e)),
-    });
-    let preview_size =
-        value_t!(args.value_of("preview_size"), u32).unwrap_or_else(|e| match e.kind {
-            ErrorKind::ArgumentNotFound => DEFAULT_PREVIEW_SIZE,
+    // the defaults depend on the screen's resolution, so they are filled in once connected
+    let scale = if args.is_present("scale") {
+        Some(value_t!(args.value_of("scale"), u32).unwrap_or_else(|e| error(&format!("{}", e))))
+    } else {
+        None
+    };
+    let preview_size = if args.is_present("preview_size") {
+        Some(
+            value_t!(args.value_of("preview_size"), u32)
+                .unwrap_or_else(|e| error(&format!("{}", e))),
+        )
+    } else {
+        None
+    };
+
+    let mut style = Style::default();
+    if let Some(shape) = args.value_of("shape") {
+        style.shape = shape.parse().unwrap_or_else(|e| error(&format!("{}", e)));
+    }
+    if args.is_present("border_width") {
+        style.border_width = value_t!(args.value_of("border_width"), u32)
+            .unwrap_or_else(|e| error(&format!("{}", e)));
+    }
+    if let Some(color) = args.value_of("border_color") {
+        style.border_color = Some(color.parse().unwrap_or_else(|e| error(&format!("{}", e))));
+    }
+    style.grid = !args.is_present("no_grid");
+    if args.is_present("grid_opacity") {
+        style.grid_opacity = value_t!(args.value_of("grid_opacity"), f32)
+            .unwrap_or_else(|e| error(&format!("{}", e)));
+        if !(0.0..=1.0).contains(&style.grid_opacity) {
+            error("Grid opacity must be between 0 and 1");
+        }
+    }
+    if let Some(highlight) = args.value_of("highlight") {
+        style.highlight = highlight
+            .parse()
+            .unwrap_or_else(|e| error(&format!("{}", e)));
+    }
+    let renderer = MagnifyingGlass::new(style);
+
+    let delay = args
+        .value_of("delay")
+        .map(|delay| parse_duration(delay).unwrap_or_else(|e| error(&format!("{}", e))));
+
+    let timeout = args
+        .value_of("timeout")
+        .map(|timeout| parse_duration(timeout).unwrap_or_else(|e| error(&format!("{}", e))));
+
+    let grab_retries =
+        value_t!(args.value_of("grab_retries"), u32).unwrap_or_else(|e| match e.kind {
+            ErrorKind::ArgumentNotFound => DEFAULT_GRAB_RETRIES,
+            _ => error(&format!("{}", e)),
+        });
+
+    let swatch_size =
+        value_t!(args.value_of("swatch_size"), u32).unwrap_or_else(|e| match e.kind {
+            ErrorKind::ArgumentNotFound => DEFAULT_SWATCH_SIZE,
             _ => error(&format!("{}", e)),
         });
+    if swatch_size == 0 || swatch_size > MAX_SWATCH_SIZE {
+        error(&format!(
+            "Swatch size must be between 1 and {}",
+            MAX_SWATCH_SIZE
+        ));
+    }
 
-    let selection = args.values_of("selection").and_then(|mut v| {
-        v.next()
-            .map_or(Some(Selection::Clipboard), |v| v.parse::<Selection>().ok())
+    let selections = args.values_of("selection").map(|values| {
+        let mut selections = Vec::new();
+        for value in values {
+            let selection = value
+                .parse::<Selection>()
+                .unwrap_or_else(|e| error(&format!("{}", e)));
+            if !selections.contains(&selection) {
+                selections.push(selection);
+            }
+        }
+        if selections.is_empty() {
+            selections.push(Selection::Clipboard);
+        }
+        selections
     });
-    let use_selection = selection.is_some();
+    let use_selection = selections.is_some();
+    let tee = args.is_present("tee");
+
+    let max_pastes = if args.is_present("max_pastes") {
+        let max_pastes =
+            value_t!(args.value_of("max_pastes"), u32).unwrap_or_else(|e| error(&format!("{}", e)));
+        if max_pastes == 0 {
+            error("Paste count must be at least 1");
+        }
+        Some(max_pastes)
+    } else {
+        None
+    };
+    let lifetime = args
+        .value_of("selection_timeout")
+        .map(|lifetime| parse_duration(lifetime).unwrap_or_else(|e| error(&format!("{}", e))));
+    let selection_options = SelectionOptions {
+        swatch_size,
+        max_pastes,
+        lifetime,
+    };
+
+    let watch_args = args.subcommand_matches("watch");
+    let interval = watch_args
+        .and_then(|args| args.value_of("interval"))
+        .map(|interval| {
+            let interval = parse_duration(interval).unwrap_or_else(|e| error(&format!("{}", e)));
+            if interval == Duration::from_secs(0) {
+                error("Interval must be longer than zero");
+            }
+            interval
+        });
+
     let background = std::env::var("XCOLOR_FOREGROUND").is_err();
 
     let mut in_parent = true;
@@ -71,10 +199,78 @@ fn run(args: &ArgMatches) -> Result<()> {
             .ok_or_else(|| anyhow!("Could not find screen"))?;
         let root = screen.root();
 
-        if let Some(color) = wait_for_location(&conn, &screen, preview_size, scale)? {
+        if let Some(args) = watch_args {
+            return watch(&conn, &screen, formatter, interval, args.is_present("json"));
+        }
+
+        if let Some(delay) = delay {
+            thread::sleep(delay);
+        }
+
+        // watch for screen changes before the first capture so none are missed,
+        // a snapshot doesn't change though
+        let freeze = args.is_present("freeze");
+        let damage = if freeze {
+            None
+        } else {
+            DamageWatch::new(&conn, screen.root())
+        };
+
+        let mut capture: Box<dyn Capture> = if freeze {
+            Box::new(FrozenCapture::new(&conn, &screen)?)
+        } else if let Some(capture) = ShmCapture::new(&conn, &screen) {
+            Box::new(capture)
+        } else if damage.is_some() {
+            // MIT-SHM is unavailable, e.g. over SSH, so avoid a round-trip on every
+            // move. Only safe with damage reports, which tell when the tile goes stale.
+            Box::new(TileCapture::new(LiveCapture::new(&screen), &screen))
+        } else {
+            Box::new(LiveCapture::new(&screen))
+        };
+
+        let (scale, preview_size) = match (scale, preview_size) {
+            (Some(scale), Some(preview_size)) => (scale, preview_size),
+            _ => {
+                let factor = dpi::scale_factor(&conn, &screen);
+                (
+                    scale.unwrap_or_else(|| dpi::scaled(DEFAULT_SCALE, factor)),
+                    preview_size.unwrap_or_else(|| dpi::scaled(DEFAULT_PREVIEW_SIZE, factor)),
+                )
+            }
+        };
+
+        let pick_options = PickOptions {
+            preview_width: preview_size,
+            scale,
+            timeout,
+            grab_retries,
+            formatter,
+            renderer: &renderer,
+        };
+
+        let picked = wait_for_location(
+            &conn,
+            &screen,
+            capture.as_mut(),
+            damage.as_ref(),
+            &pick_options,
+        )?;
+
+        // stop capturing and watching the screen, the daemon may run for a long time
+        capture.destroy(&conn);
+        drop(capture);
+        drop(damage);
+
+        if let Some(color) = picked {
             let output = formatter.format(color);
 
             if use_selection {
+                if tee {
+                    // print before forking, so the output is there when we return to the caller
+                    println!("{}", output);
+                    io::stdout().flush()?;
+                }
+
                 if background {
                     in_parent = match into_daemon()? {
                         ForkResult::Parent { .. } => true,
@@ -83,7 +279,20 @@ fn run(args: &ArgMatches) -> Result<()> {
                 }
 
                 if !(background && in_parent) {
-                    set_selection(&conn, root, &selection.unwrap(), &output)?;
+                    // ask an older daemon to exit instead of leaving it around, but
+                    // serve the selection even if the pidfile can't be written
+                    let _pidfile = PidFile::replace().unwrap_or_else(|e| {
+                        eprintln!("warning: could not write the pidfile: {}", e);
+                        None
+                    });
+                    set_selection(
+                        &conn,
+                        root,
+                        &selections.unwrap(),
+                        color,
+                        &output,
+                        &selection_options,
+                    )?;
                 }
             } else {
                 println!("{}", output);
@@ -102,6 +311,14 @@ fn main() {
     let args = get_cli().get_matches();
     if let Err(err) = run(&args) {
         eprintln!("error: {}", err);
-        std::process::exit(1);
+        let status = if err.is::<PickTimeout>() {
+            EXIT_TIMEOUT
+        } else if let Some(PickInterrupted(signal)) = err.downcast_ref() {
+            // like a shell does for a process killed by the signal
+            128 + *signal as i32
+        } else {
+            1
+        };
+        std::process::exit(status);
     }
 }
@@ -0,0 +1,74 @@
+use xcb::base as xbase;
+use xcb::base::Connection;
+use xcb::randr;
+use xcb::xproto;
+
+use crate::capture::Rect;
+use crate::util::has_extension;
+
+// Returns the rects of the active CRTCs, or `None` if RandR 1.3 isn't available
+fn crtc_rects(conn: &Connection, root: xproto::Window) -> Option<Vec<Rect>> {
+    if !has_extension(conn, randr::id()) {
+        return None;
+    }
+
+    // GetScreenResourcesCurrent needs 1.3, and the version has to be negotiated first
+    let version = randr::query_version(conn, 1, 3).get_reply().ok()?;
+    if (version.major_version(), version.minor_version()) < (1, 3) {
+        return None;
+    }
+
+    let resources = randr::get_screen_resources_current(conn, root)
+        .get_reply()
+        .ok()?;
+    let cookies: Vec<_> = resources
+        .crtcs()
+        .iter()
+        .map(|&crtc| randr::get_crtc_info(conn, crtc, resources.config_timestamp()))
+        .collect();
+
+    let mut rects = Vec::new();
+    for cookie in cookies {
+        let crtc = cookie.get_reply().ok()?;
+        // disabled CRTCs have no mode
+        if crtc.mode() != xbase::NONE && crtc.width() > 0 && crtc.height() > 0 {
+            rects.push((crtc.x(), crtc.y(), crtc.width(), crtc.height()));
+        }
+    }
+
+    Some(rects)
+}
+
+/// Returns the rects of the monitors, or of the whole screen if they can't be
+/// found out.
+pub fn monitors(conn: &Connection, screen: &xproto::Screen) -> Vec<Rect> {
+    match crtc_rects(conn, screen.root()) {
+        Some(rects) if !rects.is_empty() => rects,
+        _ => vec![(0, 0, screen.width_in_pixels(), screen.height_in_pixels())],
+    }
+}
+
+/// Returns the monitor showing `point`, or the first monitor if none does.
+pub fn monitor_at(monitors: &[Rect], (x, y): (i16, i16)) -> Rect {
+    let contains = |&&(mx, my, width, height): &&Rect| {
+        let (x, y) = (i32::from(x), i32::from(y));
+        let (mx, my) = (i32::from(mx), i32::from(my));
+        x >= mx && y >= my && x < mx + i32::from(width) && y < my + i32::from(height)
+    };
+    monitors
+        .iter()
+        .find(contains)
+        .or_else(|| monitors.first())
+        .copied()
+        .unwrap_or((0, 0, 0, 0))
+}
+
+#[test]
+fn test_monitor_at() {
+    // a 1080p monitor next to a taller 1440p one
+    let monitors = [(0, 0, 1920, 1080), (1920, 0, 2560, 1440)];
+    assert_eq!(monitor_at(&monitors, (100, 100)), monitors[0]);
+    assert_eq!(monitor_at(&monitors, (1920, 1200)), monitors[1]);
+    // the gap below the first monitor isn't part of either
+    assert_eq!(monitor_at(&monitors, (100, 1200)), monitors[0]);
+}
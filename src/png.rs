@@ -0,0 +1,203 @@
+use crate::color::ARGB;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+// Longest match of a deflate back-reference
+const MAX_MATCH: usize = 258;
+
+// Shortest match of each deflate length code and the number of extra bits that follow it
+const LENGTH_BASES: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA_BITS: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffff_u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+// Writes a single deflate block using the fixed Huffman codes. There's no
+// search for matches: it's up to the caller to say what to repeat.
+struct Deflate {
+    data: Vec<u8>,
+    bits: u32,
+    count: u32,
+}
+
+impl Deflate {
+    fn new() -> Self {
+        let mut deflate = Deflate {
+            data: Vec::new(),
+            bits: 0,
+            count: 0,
+        };
+        // the final block, compressed with fixed codes
+        deflate.write_bits(1, 1);
+        deflate.write_bits(1, 2);
+        deflate
+    }
+
+    // Values are packed starting from their least significant bit
+    fn write_bits(&mut self, value: u32, count: u32) {
+        self.bits |= value << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.data.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    // Huffman codes are packed starting from their most significant bit
+    fn write_code(&mut self, code: u32, len: u32) {
+        self.write_bits(code.reverse_bits() >> (32 - len), len);
+    }
+
+    fn write_symbol(&mut self, symbol: u16) {
+        let symbol = u32::from(symbol);
+        match symbol {
+            0..=143 => self.write_code(0x30 + symbol, 8),
+            144..=255 => self.write_code(0x190 + symbol - 144, 9),
+            256..=279 => self.write_code(symbol - 256, 7),
+            _ => self.write_code(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    fn literal(&mut self, byte: u8) {
+        self.write_symbol(byte.into());
+    }
+
+    // Repeats the previous byte `len` times, `len` being 0 or at least 3
+    fn repeat(&mut self, mut len: usize) {
+        while len > 0 {
+            // don't leave a remainder too short to be a match
+            let n = if len > MAX_MATCH && len - MAX_MATCH < 3 {
+                len - 3
+            } else {
+                len.min(MAX_MATCH)
+            };
+            len -= n;
+
+            let code = LENGTH_BASES
+                .iter()
+                .rposition(|&base| usize::from(base) <= n)
+                .unwrap();
+            self.write_symbol(257 + code as u16);
+            let extra = n - usize::from(LENGTH_BASES[code]);
+            self.write_bits(extra as u32, LENGTH_EXTRA_BITS[code].into());
+
+            // distance 1 has the first distance code, without extra bits
+            self.write_code(0, 5);
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        // end of block
+        self.write_symbol(256);
+        if self.count > 0 {
+            self.data.push(self.bits as u8);
+        }
+        self.data
+    }
+}
+
+// Compresses `len` zero bytes into a zlib stream
+fn zlib_zeros(len: usize) -> Vec<u8> {
+    // write the first zero and repeat it, unless there are too few to repeat
+    let mut deflate = Deflate::new();
+    if len < 4 {
+        for _ in 0..len {
+            deflate.literal(0);
+        }
+    } else {
+        deflate.literal(0);
+        deflate.repeat(len - 1);
+    }
+
+    // every byte adds 1 to the second sum of the checksum
+    let adler = ((len % 65521) as u32) << 16 | 1;
+
+    // deflate with a 32K window, no preset dictionary
+    let mut stream = vec![0x78, 0x01];
+    stream.extend(deflate.finish());
+    stream.extend_from_slice(&adler.to_be_bytes());
+    stream
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Encodes a `width` by `height` PNG image filled with `color`.
+pub fn solid(color: ARGB, width: u32, height: u32) -> Vec<u8> {
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // 1 bit per pixel indexing the palette, default compression, filter and interlace methods
+    header.extend_from_slice(&[1, 3, 0, 0, 0]);
+
+    // every pixel is the only palette entry, and every scanline starts with its
+    // filter type, which is none here: the image is all zeros
+    let scanline = 1 + (width as usize + 7) / 8;
+    let image = zlib_zeros(scanline * height as usize);
+
+    let mut png = SIGNATURE.to_vec();
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"PLTE", &[color.r, color.g, color.b]);
+    write_chunk(&mut png, b"IDAT", &image);
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+#[test]
+fn test_checksums() {
+    assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    assert_eq!(crc32(b"IEND"), 0xae42_6082);
+
+    // the Adler-32 of 70000 zeros, where the second sum wraps around
+    let stream = zlib_zeros(70000);
+    assert_eq!(stream[stream.len() - 4..], 0x117f_0001u32.to_be_bytes());
+}
+
+#[test]
+fn test_zlib_zeros() {
+    // a literal followed by a 258 byte copy of it
+    assert_eq!(
+        zlib_zeros(259),
+        [0x78, 0x01, 0x63, 0x18, 0x05, 0x00, 0x01, 0x03, 0x00, 0x01]
+    );
+    assert_eq!(zlib_zeros(0), [0x78, 0x01, 0x03, 0x00, 0, 0, 0, 1]);
+}
+
+#[test]
+fn test_solid() {
+    let png = solid(ARGB::new(0xff, 0x12, 0x34, 0x56), 2, 3);
+    assert_eq!(png[..8], SIGNATURE);
+    assert_eq!(&png[12..16], b"IHDR");
+    assert_eq!(png[16..20], 2u32.to_be_bytes());
+    assert_eq!(png[20..24], 3u32.to_be_bytes());
+    assert_eq!(&png[37..41], b"PLTE");
+    assert_eq!(png[41..44], [0x12, 0x34, 0x56]);
+    assert_eq!(&png[png.len() - 8..png.len() - 4], b"IEND");
+
+    // the largest swatch takes less than 1/1000th of its 48 MiB of pixels
+    assert!(solid(ARGB::WHITE, 4096, 4096).len() < 48 * 1024);
+}
@@ -0,0 +1,412 @@
+use anyhow::{anyhow, Result};
+use x11::xcursor::{XcursorImageCreate, XcursorImageDestroy, XcursorImageLoadCursor};
+use xcb::base as xbase;
+use xcb::base::Connection;
+use xcb::render;
+use xcb::xproto;
+
+use crate::capture::Rect;
+use crate::location::GRAB_MASK;
+use crate::monitor::monitor_at;
+use crate::pixel::PixelSquare;
+use crate::util::has_extension;
+
+// Glyph of the crosshair in the standard cursor font (XC_crosshair); its mask is the next glyph
+const CROSSHAIR_GLYPH: u16 = 34;
+
+// Space between the pointer and the magnifier window
+const WINDOW_GAP: i32 = 8;
+
+// Size of a PutImage request without its data
+const PUT_IMAGE_HEADER_SIZE: usize = 24;
+
+/// Shows the magnified screenshot around the pointer while picking.
+pub trait Preview {
+    /// The cursor to show while the pointer is grabbed.
+    fn cursor(&self) -> u32;
+
+    /// Shows the magnifier next to or around the pointer at `point`, calling
+    /// `draw` to draw it into a square of pixels as wide as the preview.
+    fn show(
+        &mut self,
+        conn: &Connection,
+        draw: &dyn Fn(&mut PixelSquare<&mut [u32]>),
+        point: (i16, i16),
+    ) -> Result<()>;
+
+    /// Frees the resources used on the X server.
+    fn destroy(&mut self, conn: &Connection);
+
+    /// Returns the part of the screen the preview covers, if it's drawn onto
+    /// the screen itself.
+    fn area(&self) -> Option<Rect> {
+        None
+    }
+}
+
+/// Returns the preview to use: a cursor if the X server can show one as large
+/// as `preview_width`, and a window following the pointer otherwise. The
+/// window keeps `clearance` pixels away from the pointer so it doesn't end up
+/// in the screenshots it shows.
+pub fn create_preview(
+    conn: &Connection,
+    screen: &xproto::Screen,
+    monitors: &[Rect],
+    preview_width: u32,
+    clearance: i32,
+) -> Result<Box<dyn Preview>> {
+    if supports_cursor(conn, screen, preview_width) {
+        Ok(Box::new(CursorPreview::new(preview_width)))
+    } else {
+        Ok(Box::new(WindowPreview::new(
+            conn,
+            screen,
+            monitors,
+            preview_width,
+            clearance,
+        )?))
+    }
+}
+
+// Colored cursors need RENDER, and some servers limit the size of cursors
+fn supports_cursor(conn: &Connection, screen: &xproto::Screen, preview_width: u32) -> bool {
+    if !has_extension(conn, render::id()) {
+        return false;
+    }
+
+    let width = preview_width.min(u16::MAX.into()) as u16;
+    xproto::query_best_size(
+        conn,
+        xproto::QUERY_SHAPE_OF_LARGEST_CURSOR as u8,
+        screen.root(),
+        width,
+        width,
+    )
+    .get_reply()
+    .map(|size| size.width() >= width && size.height() >= width)
+    .unwrap_or(false)
+}
+
+/// Shows the magnifier as the cursor of the grabbed pointer.
+pub struct CursorPreview {
+    cursor: u32,
+    preview_width: u32,
+}
+
+impl CursorPreview {
+    pub fn new(preview_width: u32) -> Self {
+        CursorPreview {
+            cursor: xbase::NONE,
+            preview_width,
+        }
+    }
+}
+
+// Creates a new `XcursorImage`, draws the picker into it and loads it, returning the id for a `Cursor`
+fn create_new_xcursor(
+    conn: &Connection,
+    draw: &dyn Fn(&mut PixelSquare<&mut [u32]>),
+    preview_width: u32,
+) -> u32 {
+    unsafe {
+        let cursor_image = XcursorImageCreate(preview_width as i32, preview_width as i32);
+
+        // set the "hot spot" - this is where the pointer actually is inside the image
+        (*cursor_image).xhot = preview_width / 2;
+        (*cursor_image).yhot = preview_width / 2;
+
+        // get pixel data as a mutable Rust slice
+        let mut cursor_pixels =
+            PixelSquare::from_raw_parts((*cursor_image).pixels, preview_width as usize);
+
+        // draw our custom image
+        draw(&mut cursor_pixels);
+
+        // convert our XcursorImage into a cursor
+        let cursor_id = XcursorImageLoadCursor(conn.get_raw_dpy(), cursor_image) as u32;
+
+        // free the XcursorImage
+        XcursorImageDestroy(cursor_image);
+
+        cursor_id
+    }
+}
+
+impl Preview for CursorPreview {
+    fn cursor(&self) -> u32 {
+        self.cursor
+    }
+
+    fn show(
+        &mut self,
+        conn: &Connection,
+        draw: &dyn Fn(&mut PixelSquare<&mut [u32]>),
+        _point: (i16, i16),
+    ) -> Result<()> {
+        let cursor = create_new_xcursor(conn, draw, self.preview_width);
+
+        // this is ignored by the server until the pointer is grabbed
+        let updated = xproto::change_active_pointer_grab_checked(
+            conn,
+            cursor,
+            xbase::CURRENT_TIME,
+            GRAB_MASK,
+        )
+        .request_check();
+
+        if let Err(err) = updated {
+            xproto::free_cursor(conn, cursor);
+            return Err(err.into());
+        }
+
+        self.destroy(conn);
+        self.cursor = cursor;
+
+        Ok(())
+    }
+
+    fn destroy(&mut self, conn: &Connection) {
+        if self.cursor != xbase::NONE {
+            xproto::free_cursor(conn, self.cursor);
+            self.cursor = xbase::NONE;
+        }
+    }
+}
+
+/// Shows the magnifier in a window next to the pointer, for servers that
+/// can't show it as a cursor.
+pub struct WindowPreview {
+    window: xproto::Window,
+    colormap: xproto::Colormap,
+    gc: xproto::Gcontext,
+    cursor: xproto::Cursor,
+    depth: u8,
+    preview_width: u32,
+    // distance from the pointer to the window, so it stays out of the captured area
+    gap: i32,
+    monitors: Vec<Rect>,
+    little_endian: bool,
+    // where the window was last shown, `None` until it's mapped
+    position: Option<(i16, i16)>,
+}
+
+// Returns a 32-bit TrueColor visual, which gives the window an alpha channel under a compositor
+fn find_argb_visual(screen: &xproto::Screen) -> Option<xproto::Visualid> {
+    screen
+        .allowed_depths()
+        .filter(|depth| depth.depth() == 32)
+        .flat_map(|depth| depth.visuals())
+        .find(|visual| visual.class() == xproto::VISUAL_CLASS_TRUE_COLOR as u8)
+        .map(|visual| visual.visual_id())
+}
+
+// Places a `size` wide window `gap` pixels away from `point`, moving it to the
+// other side of the pointer when it would leave the monitor. If neither side
+// has room, the window goes to the edge of the monitor farthest from the pointer.
+fn place_window((x, y): (i16, i16), size: i32, gap: i32, monitor: Rect) -> (i16, i16) {
+    fn place(pointer: i32, size: i32, gap: i32, start: i32, len: i32) -> i32 {
+        let end = start + len;
+        if pointer + gap + size <= end {
+            pointer + gap
+        } else if pointer - gap - size >= start {
+            pointer - gap - size
+        } else if end - pointer > pointer - start {
+            end - size
+        } else {
+            start
+        }
+    }
+
+    let (mx, my, width, height) = monitor;
+    (
+        place(x.into(), size, gap, mx.into(), width.into()) as i16,
+        place(y.into(), size, gap, my.into(), height.into()) as i16,
+    )
+}
+
+impl WindowPreview {
+    pub fn new(
+        conn: &Connection,
+        screen: &xproto::Screen,
+        monitors: &[Rect],
+        preview_width: u32,
+        clearance: i32,
+    ) -> Result<Self> {
+        let root = screen.root();
+        let width = preview_width.min(u16::MAX.into()) as u16;
+
+        let (depth, visual) = match find_argb_visual(screen) {
+            Some(visual) => (32, visual),
+            None => (screen.root_depth(), screen.root_visual()),
+        };
+        if depth != 32 && depth != 24 {
+            return Err(anyhow!("Unsupported color depth"));
+        }
+
+        let colormap = conn.generate_id();
+        xproto::create_colormap(
+            conn,
+            xproto::COLORMAP_ALLOC_NONE as u8,
+            colormap,
+            root,
+            visual,
+        );
+
+        // override-redirect keeps window managers from decorating or moving the window
+        let window = conn.generate_id();
+        xproto::create_window_checked(
+            conn,
+            depth,
+            window,
+            root,
+            0,
+            0,
+            width,
+            width,
+            0,
+            xproto::WINDOW_CLASS_INPUT_OUTPUT as u16,
+            visual,
+            &[
+                (xproto::CW_BACK_PIXEL, 0),
+                (xproto::CW_BORDER_PIXEL, 0),
+                (xproto::CW_OVERRIDE_REDIRECT, 1),
+                (xproto::CW_COLORMAP, colormap),
+            ],
+        )
+        .request_check()?;
+
+        let gc = conn.generate_id();
+        xproto::create_gc(conn, gc, window, &[]);
+
+        // the magnifier isn't under the pointer, so show where it actually is
+        let font = conn.generate_id();
+        xproto::open_font(conn, font, "cursor");
+        let cursor = conn.generate_id();
+        xproto::create_glyph_cursor(
+            conn,
+            cursor,
+            font,
+            font,
+            CROSSHAIR_GLYPH,
+            CROSSHAIR_GLYPH + 1,
+            0,
+            0,
+            0,
+            u16::MAX,
+            u16::MAX,
+            u16::MAX,
+        );
+        xproto::close_font(conn, font);
+
+        Ok(WindowPreview {
+            window,
+            colormap,
+            gc,
+            cursor,
+            depth,
+            preview_width,
+            gap: clearance + WINDOW_GAP,
+            monitors: monitors.to_vec(),
+            little_endian: conn.get_setup().image_byte_order()
+                == xproto::IMAGE_ORDER_LSB_FIRST as u8,
+            position: None,
+        })
+    }
+
+    // Uploads the image in as many requests as the server's request size limit requires
+    fn put_image(&self, conn: &Connection, pixels: &[u32]) {
+        let width = self.preview_width as usize;
+        let mut data = Vec::with_capacity(pixels.len() * 4);
+        for pixel in pixels {
+            let bytes = if self.little_endian {
+                pixel.to_le_bytes()
+            } else {
+                pixel.to_be_bytes()
+            };
+            data.extend_from_slice(&bytes);
+        }
+
+        let max_size = conn.get_maximum_request_length() as usize * 4 - PUT_IMAGE_HEADER_SIZE;
+        let rows_per_request = (max_size / (width * 4)).max(1);
+        for (i, rows) in data.chunks(rows_per_request * width * 4).enumerate() {
+            xproto::put_image(
+                conn,
+                xproto::IMAGE_FORMAT_Z_PIXMAP as u8,
+                self.window,
+                self.gc,
+                width as u16,
+                (rows.len() / (width * 4)) as u16,
+                0,
+                (i * rows_per_request) as i16,
+                0,
+                self.depth,
+                rows,
+            );
+        }
+    }
+}
+
+impl Preview for WindowPreview {
+    fn cursor(&self) -> u32 {
+        self.cursor
+    }
+
+    fn show(
+        &mut self,
+        conn: &Connection,
+        draw: &dyn Fn(&mut PixelSquare<&mut [u32]>),
+        point: (i16, i16),
+    ) -> Result<()> {
+        let width = self.preview_width as usize;
+        let mut pixels = vec![0; width * width];
+        draw(&mut PixelSquare::new(&mut pixels[..], width));
+
+        let monitor = monitor_at(&self.monitors, point);
+        let (x, y) = place_window(point, width as i32, self.gap, monitor);
+        xproto::configure_window(
+            conn,
+            self.window,
+            &[
+                (xproto::CONFIG_WINDOW_X as u16, x as u32),
+                (xproto::CONFIG_WINDOW_Y as u16, y as u32),
+                (
+                    xproto::CONFIG_WINDOW_STACK_MODE as u16,
+                    xproto::STACK_MODE_ABOVE,
+                ),
+            ],
+        );
+        if self.position.is_none() {
+            xproto::map_window(conn, self.window);
+        }
+        self.position = Some((x, y));
+        self.put_image(conn, &pixels);
+
+        Ok(())
+    }
+
+    fn destroy(&mut self, conn: &Connection) {
+        xproto::destroy_window(conn, self.window);
+        xproto::free_gc(conn, self.gc);
+        xproto::free_colormap(conn, self.colormap);
+        xproto::free_cursor(conn, self.cursor);
+    }
+
+    fn area(&self) -> Option<Rect> {
+        let width = self.preview_width as u16;
+        self.position.map(|(x, y)| (x, y, width, width))
+    }
+}
+
+#[test]
+fn test_place_window() {
+    let monitor = (0, 0, 1000, 800);
+    assert_eq!(place_window((100, 100), 200, 20, monitor), (120, 120));
+    // flipped to the other side of the pointer near the right and bottom edges
+    assert_eq!(place_window((900, 100), 200, 20, monitor), (680, 120));
+    assert_eq!(place_window((100, 700), 200, 20, monitor), (120, 480));
+    // kept on the monitor under the pointer rather than the next one
+    let monitor = (1000, 0, 600, 800);
+    assert_eq!(place_window((1500, 100), 200, 20, monitor), (1280, 120));
+    // without room on either side, as far from the pointer as possible
+    assert_eq!(place_window((1250, 100), 200, 200, monitor), (1400, 300));
+}
@@ -5,11 +5,16 @@ use std::io;
 use std::os::unix::io::AsRawFd;
 use std::os::unix::io::IntoRawFd;
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 use xcb::base as xbase;
 use xcb::base::Connection;
 use xcb::xproto;
 
 use crate::atoms;
+use crate::color::ARGB;
+use crate::event::{wait_for_event, Wait};
+use crate::png;
+use crate::signal;
 
 pub fn into_daemon() -> Result<ForkResult> {
     match fork()? {
@@ -35,6 +40,7 @@ pub fn into_daemon() -> Result<ForkResult> {
     }
 }
 
+#[derive(PartialEq)]
 pub enum Selection {
     Primary,
     Secondary,
@@ -65,23 +71,612 @@ impl Selection {
 }
 
 // The selection daemon presented here is not a perfect implementation of the
-// ICCCM recommendation. Currently, it does not support large transfers and does
-// not verify that the requestor has received the data by monitoring for atom
-// deletion. Additionally, we do not support MULTIPLE and TIMESTAMP targets even
-// though those are required by the spec. However, this implements just enough
-// of the spec to work well enough in practice as color codes do not tend to be
-// that large. However, this assumption could of course fail with custom
-// templates.
+// ICCCM recommendation: for example, ongoing INCR transfers are abandoned when
+// we lose ownership of the selection. However, this implements enough of the
+// spec to work well in practice.
+
+/// Controls what is offered on the selection and how long it's served for.
+pub struct SelectionOptions {
+    /// Width and height of the `image/png` swatch
+    pub swatch_size: u32,
+    /// Exit after the selection has been pasted this many times
+    pub max_pastes: Option<u32>,
+    /// Exit after serving the selection for this long
+    pub lifetime: Option<Duration>,
+}
+
+// Largest amount of data written to a property in one request. Anything larger
+// is sent using the INCR protocol.
+const MAX_CHUNK_SIZE: usize = 256 * 1024;
+
+// Size of the `ChangeProperty` request header, which counts towards the maximum request length
+const CHANGE_PROPERTY_HEADER_SIZE: usize = 24;
+
+// Returns the largest amount of data to write to a property in one request,
+// given the server's maximum request length in 4-byte units. It's kept a
+// multiple of 4 so chunks never split 16 or 32-bit items.
+fn chunk_size(max_request_length: usize) -> usize {
+    let max_request_size = max_request_length * 4;
+    MAX_CHUNK_SIZE.min(max_request_size - CHANGE_PROPERTY_HEADER_SIZE) & !3
+}
+
+// The selection converted to some target, ready to be stored in a property
+struct Conversion {
+    type_: xproto::Atom,
+    format: u8,
+    // items of `format` bits each, in native byte order
+    data: Vec<u8>,
+}
+
+impl Conversion {
+    fn bytes(type_: xproto::Atom, data: Vec<u8>) -> Self {
+        Conversion {
+            type_,
+            format: 8,
+            data,
+        }
+    }
+
+    fn shorts(type_: xproto::Atom, items: &[u16]) -> Self {
+        Conversion {
+            type_,
+            format: 16,
+            data: items.iter().flat_map(|item| item.to_ne_bytes()).collect(),
+        }
+    }
+
+    fn longs(type_: xproto::Atom, items: &[u32]) -> Self {
+        Conversion {
+            type_,
+            format: 32,
+            data: items.iter().flat_map(|item| item.to_ne_bytes()).collect(),
+        }
+    }
+}
+
+fn change_property<'a>(
+    conn: &'a Connection,
+    window: xproto::Window,
+    property: xproto::Atom,
+    type_: xproto::Atom,
+    format: u8,
+    data: &[u8],
+) -> xbase::VoidCookie<'a> {
+    let mode = xproto::PROP_MODE_REPLACE as u8;
+    match format {
+        16 => {
+            let items: Vec<u16> = data
+                .chunks_exact(2)
+                .map(|item| u16::from_ne_bytes([item[0], item[1]]))
+                .collect();
+            xproto::change_property_checked(conn, mode, window, property, type_, format, &items)
+        }
+        32 => {
+            let items: Vec<u32> = data
+                .chunks_exact(4)
+                .map(|item| u32::from_ne_bytes([item[0], item[1], item[2], item[3]]))
+                .collect();
+            xproto::change_property_checked(conn, mode, window, property, type_, format, &items)
+        }
+        _ => xproto::change_property_checked(conn, mode, window, property, type_, format, data),
+    }
+}
+
+// Converts to ISO 8859-1 as required by the STRING target, replacing
+// characters that can't be represented
+fn to_latin1(string: &str) -> Vec<u8> {
+    string
+        .chars()
+        .map(|c| if (c as u32) <= 0xff { c as u8 } else { b'?' })
+        .collect()
+}
+
+fn to_ascii(string: &str) -> Vec<u8> {
+    string
+        .chars()
+        .map(|c| if c.is_ascii() { c as u8 } else { b'?' })
+        .collect()
+}
+
+fn escape_html(string: &str) -> String {
+    let mut escaped = String::with_capacity(string.len());
+    for c in string.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Renders the output next to a swatch of the color, so pasting into rich text editors shows it
+fn to_html(color: ARGB, string: &str) -> String {
+    format!(
+        concat!(
+            "<meta charset=\"utf-8\">",
+            "<span style=\"background-color: #{:02x}{:02x}{:02x}; ",
+            "border: 1px solid #808080; font-family: monospace;\">",
+            "&nbsp;&nbsp;&nbsp;&nbsp;</span> <code>{}</code>"
+        ),
+        color.r,
+        color.g,
+        color.b,
+        escape_html(string)
+    )
+}
+
+// The `application/x-color` target used by GTK and GIMP: 16-bit RGBA values
+fn to_x_color(color: ARGB) -> [u16; 4] {
+    fn widen(n: u8) -> u16 {
+        u16::from(n) * 0x101
+    }
+    [
+        widen(color.r),
+        widen(color.g),
+        widen(color.b),
+        widen(color.a),
+    ]
+}
+
+// An INCR transfer in progress: every time the requestor deletes `property`,
+// the next chunk of `conversion` is written to it
+struct Transfer {
+    requestor: xproto::Window,
+    property: xproto::Atom,
+    conversion: Conversion,
+    offset: usize,
+}
+
+impl Transfer {
+    // Returns the next chunk of at most `chunk_size` bytes and moves past it.
+    // The transfer ends with a zero-length chunk.
+    fn next_chunk(&mut self, chunk_size: usize) -> &[u8] {
+        let start = self.offset;
+        let end = (start + chunk_size).min(self.conversion.data.len());
+        self.offset = end;
+        &self.conversion.data[start..end]
+    }
+}
+
+// A request is one paste however many of the selection's data targets it
+// converts, so that `MULTIPLE` asking for several formats counts once. Requests
+// for information only, like `TARGETS`, and the clipboard manager saving the
+// selection aren't pastes.
+fn is_paste(converted: &[xproto::Atom], data_targets: &[xproto::Atom], from_manager: bool) -> bool {
+    !from_manager && converted.iter().any(|target| data_targets.contains(target))
+}
+
+struct Owner<'a> {
+    conn: &'a Connection,
+    timestamp: xproto::Timestamp,
+    color: ARGB,
+    string: &'a str,
+    // the `image/png` swatch, encoded once up front
+    png: Vec<u8>,
+    chunk_size: usize,
+    transfers: Vec<Transfer>,
+    pastes: u32,
+}
+
+impl<'a> Owner<'a> {
+    fn new(
+        conn: &'a Connection,
+        timestamp: xproto::Timestamp,
+        color: ARGB,
+        string: &'a str,
+        swatch_size: u32,
+    ) -> Self {
+        Owner {
+            conn,
+            timestamp,
+            color,
+            string,
+            png: png::solid(color, swatch_size, swatch_size),
+            chunk_size: chunk_size(conn.get_maximum_request_length() as usize),
+            transfers: Vec::new(),
+            pastes: 0,
+        }
+    }
+
+    // The targets that hold the selection's data, as opposed to information about the selection
+    fn data_targets(&self) -> Result<Vec<xproto::Atom>> {
+        Ok(vec![
+            atoms::get(self.conn, "UTF8_STRING")?,
+            xproto::ATOM_STRING,
+            atoms::get(self.conn, "TEXT")?,
+            atoms::get(self.conn, "text/plain;charset=utf-8")?,
+            atoms::get(self.conn, "text/plain")?,
+            atoms::get(self.conn, "text/html")?,
+            atoms::get(self.conn, "application/x-color")?,
+            atoms::get(self.conn, "image/png")?,
+        ])
+    }
+
+    // Asks the clipboard manager, if there is one, to save the contents of
+    // CLIPBOARD so it survives us exiting. The manager replies with a
+    // `SelectionNotify` event once it's done.
+    fn request_save(&self, window: xproto::Window) -> Result<()> {
+        let manager = atoms::get(self.conn, "CLIPBOARD_MANAGER")?;
+        if xproto::get_selection_owner(self.conn, manager)
+            .get_reply()?
+            .owner()
+            == xbase::NONE
+        {
+            return Ok(());
+        }
+
+        let save_targets = atoms::get(self.conn, "SAVE_TARGETS")?;
+        let property = atoms::get(self.conn, "_XCOLOR_SAVE_TARGETS")?;
+        let targets = Conversion::longs(xproto::ATOM_ATOM, &self.data_targets()?);
+        change_property(
+            self.conn,
+            window,
+            property,
+            targets.type_,
+            32,
+            &targets.data,
+        )
+        .request_check()?;
+
+        xproto::convert_selection_checked(
+            self.conn,
+            window,
+            manager,
+            save_targets,
+            property,
+            self.timestamp,
+        )
+        .request_check()?;
+        Ok(())
+    }
+
+    // Counts a request that converted `targets` as a paste if it asked for the selection's data
+    fn count_paste(&mut self, requestor: xproto::Window, targets: &[xproto::Atom]) -> Result<()> {
+        let manager = atoms::get(self.conn, "CLIPBOARD_MANAGER")?;
+        let manager_owner = xproto::get_selection_owner(self.conn, manager)
+            .get_reply()?
+            .owner();
+        let from_manager = manager_owner != xbase::NONE && manager_owner == requestor;
+
+        if is_paste(targets, &self.data_targets()?, from_manager) {
+            self.pastes += 1;
+        }
+        Ok(())
+    }
+
+    // Converts the selection to `target`, returns `None` if that's not possible
+    fn convert(&self, target: xproto::Atom) -> Result<Option<Conversion>> {
+        let utf8_string = atoms::get(self.conn, "UTF8_STRING")?;
+        let string = xproto::ATOM_STRING;
+        let text = atoms::get(self.conn, "TEXT")?;
+        let text_plain = atoms::get(self.conn, "text/plain")?;
+        let text_plain_utf8 = atoms::get(self.conn, "text/plain;charset=utf-8")?;
+        let text_html = atoms::get(self.conn, "text/html")?;
+        let x_color = atoms::get(self.conn, "application/x-color")?;
+        let image_png = atoms::get(self.conn, "image/png")?;
+        let targets = atoms::get(self.conn, "TARGETS")?;
+        let multiple = atoms::get(self.conn, "MULTIPLE")?;
+        let timestamp = atoms::get(self.conn, "TIMESTAMP")?;
+
+        Ok(if target == utf8_string || target == text_plain_utf8 {
+            Some(Conversion::bytes(target, self.string.as_bytes().to_vec()))
+        } else if target == string {
+            Some(Conversion::bytes(string, to_latin1(self.string)))
+        } else if target == text {
+            // TEXT lets the owner pick the encoding, prefer the one older clients understand
+            Some(if self.string.chars().all(|c| (c as u32) <= 0xff) {
+                Conversion::bytes(string, to_latin1(self.string))
+            } else {
+                Conversion::bytes(utf8_string, self.string.as_bytes().to_vec())
+            })
+        } else if target == text_plain {
+            Some(Conversion::bytes(text_plain, to_ascii(self.string)))
+        } else if target == text_html {
+            Some(Conversion::bytes(
+                text_html,
+                to_html(self.color, self.string).into_bytes(),
+            ))
+        } else if target == x_color {
+            Some(Conversion::shorts(x_color, &to_x_color(self.color)))
+        } else if target == image_png {
+            Some(Conversion::bytes(image_png, self.png.clone()))
+        } else if target == targets {
+            let mut targets = vec![targets, multiple, timestamp];
+            targets.extend(self.data_targets()?);
+            Some(Conversion::longs(xproto::ATOM_ATOM, &targets))
+        } else if target == timestamp {
+            Some(Conversion::longs(xproto::ATOM_INTEGER, &[self.timestamp]))
+        } else {
+            None
+        })
+    }
+
+    // Stores `conversion` in `property` on `requestor`, starting an INCR
+    // transfer if it doesn't fit in a single request
+    fn store(
+        &mut self,
+        requestor: xproto::Window,
+        property: xproto::Atom,
+        conversion: Conversion,
+    ) -> Result<()> {
+        if conversion.data.len() <= self.chunk_size {
+            return Ok(change_property(
+                self.conn,
+                requestor,
+                property,
+                conversion.type_,
+                conversion.format,
+                &conversion.data,
+            )
+            .request_check()?);
+        }
+
+        // we need to know when the requestor deletes the property to send the next chunk
+        xproto::change_window_attributes_checked(
+            self.conn,
+            requestor,
+            &[(
+                xproto::CW_EVENT_MASK,
+                xproto::EVENT_MASK_PROPERTY_CHANGE | xproto::EVENT_MASK_STRUCTURE_NOTIFY,
+            )],
+        )
+        .request_check()?;
+
+        let incr = atoms::get(self.conn, "INCR")?;
+        let size = Conversion::longs(incr, &[conversion.data.len() as u32]);
+        change_property(self.conn, requestor, property, incr, 32, &size.data).request_check()?;
+
+        self.transfers.push(Transfer {
+            requestor,
+            property,
+            conversion,
+            offset: 0,
+        });
+        Ok(())
+    }
+
+    // Handles a `MULTIPLE` request: `property` on `requestor` holds a list of
+    // (target, property) atom pairs to convert. Pairs that can't be converted
+    // have their property replaced with `None`, as required by the ICCCM.
+    // Returns the targets that were converted, or `None` if the list is malformed.
+    fn store_multiple(
+        &mut self,
+        requestor: xproto::Window,
+        property: xproto::Atom,
+    ) -> Result<Option<Vec<xproto::Atom>>> {
+        let reply = xproto::get_property(
+            self.conn,
+            false,
+            requestor,
+            property,
+            xproto::GET_PROPERTY_TYPE_ANY,
+            0,
+            u32::MAX,
+        )
+        .get_reply()?;
+
+        if reply.format() != 32 {
+            return Ok(None);
+        }
+
+        let mut converted = Vec::new();
+        let mut pairs = reply.value::<u32>().to_vec();
+        for pair in pairs.chunks_exact_mut(2) {
+            let (target, target_property) = (pair[0], pair[1]);
+            let conversion = match target_property {
+                xbase::NONE => None,
+                _ => self.convert(target)?,
+            };
+            match conversion {
+                Some(conversion) => {
+                    self.store(requestor, target_property, conversion)?;
+                    converted.push(target);
+                }
+                None => pair[1] = xbase::NONE,
+            }
+        }
+
+        let pairs = Conversion::longs(reply.type_(), &pairs);
+        change_property(self.conn, requestor, property, pairs.type_, 32, &pairs.data)
+            .request_check()?;
+
+        Ok(Some(converted))
+    }
+
+    // Converts the selection as asked by `event`, returning the property the
+    // result was stored in, or `None` if the request should be refused
+    fn convert_request(
+        &mut self,
+        event: &xproto::SelectionRequestEvent,
+    ) -> Result<Option<xproto::Atom>> {
+        // requests from before we became the owner are meant for the previous owner
+        if event.time() != xbase::CURRENT_TIME && event.time() < self.timestamp {
+            return Ok(None);
+        }
+
+        // obsolete clients don't specify a property, the ICCCM says to use the target's name
+        let target = event.target();
+        let property = match event.property() {
+            xbase::NONE => target,
+            property => property,
+        };
+
+        let converted = if target == atoms::get(self.conn, "MULTIPLE")? {
+            // the list of targets to convert is stored in the property, so there must be one
+            if event.property() == xbase::NONE {
+                None
+            } else {
+                self.store_multiple(event.requestor(), property)?
+            }
+        } else {
+            match self.convert(target)? {
+                Some(conversion) => {
+                    self.store(event.requestor(), property, conversion)?;
+                    Some(vec![target])
+                }
+                None => None,
+            }
+        };
+
+        match converted {
+            Some(targets) => {
+                self.count_paste(event.requestor(), &targets)?;
+                Ok(Some(property))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Answers a `SelectionRequest`. Failures (e.g. the requestor window
+    // vanishing) only refuse that request so we can keep serving others.
+    fn handle_request(&mut self, event: &xproto::SelectionRequestEvent) {
+        let transfers = self.transfers.len();
+        let property = match self.convert_request(event) {
+            Ok(Some(property)) => property,
+            Ok(None) => xbase::NONE,
+            Err(_) => {
+                // forget any INCR transfers this request started
+                self.transfers.truncate(transfers);
+                xbase::NONE
+            }
+        };
+
+        let response = xproto::SelectionNotifyEvent::new(
+            event.time(),
+            event.requestor(),
+            event.selection(),
+            event.target(),
+            property,
+        );
+
+        // there is nobody left to tell if the requestor is gone
+        let _ = xproto::send_event_checked(self.conn, false, event.requestor(), 0, &response)
+            .request_check();
+    }
+
+    // Sends the next chunk of the transfer at `index`
+    fn send_chunk(&mut self, index: usize) -> Result<()> {
+        let transfer = &mut self.transfers[index];
+        let (requestor, property) = (transfer.requestor, transfer.property);
+        let (type_, format) = (transfer.conversion.type_, transfer.conversion.format);
+        let chunk = transfer.next_chunk(self.chunk_size);
+        let done = chunk.is_empty();
+
+        change_property(self.conn, requestor, property, type_, format, chunk).request_check()?;
+
+        if done {
+            self.transfers.remove(index);
+            // the transfer is complete even if the requestor has vanished since
+            let _ = self.forget_requestor(requestor);
+        }
+
+        Ok(())
+    }
+
+    // Sends the next chunk of an INCR transfer once the requestor has deleted
+    // the previous one. A transfer that fails is abandoned.
+    fn handle_property_notify(&mut self, event: &xproto::PropertyNotifyEvent) {
+        if event.state() != xproto::PROPERTY_DELETE as u8 {
+            return;
+        }
+
+        let index = self
+            .transfers
+            .iter()
+            .position(|t| t.requestor == event.window() && t.property == event.atom());
+        if let Some(index) = index {
+            if self.send_chunk(index).is_err() {
+                self.transfers.remove(index);
+            }
+        }
+    }
+
+    fn handle_destroy_notify(&mut self, event: &xproto::DestroyNotifyEvent) {
+        self.transfers.retain(|t| t.requestor != event.window());
+    }
+
+    // Stops listening to events from `requestor` once all its transfers are done
+    fn forget_requestor(&self, requestor: xproto::Window) -> Result<()> {
+        if self.transfers.iter().all(|t| t.requestor != requestor) {
+            xproto::change_window_attributes_checked(
+                self.conn,
+                requestor,
+                &[(xproto::CW_EVENT_MASK, xproto::EVENT_MASK_NO_EVENT)],
+            )
+            .request_check()?;
+        }
+        Ok(())
+    }
+}
+
+// Gets a real server timestamp by making a zero-length property change on
+// `window` (which must be selecting `PropertyChange` events) and waiting for the
+// resulting `PropertyNotify` event
+fn get_timestamp(conn: &Connection, window: xproto::Window) -> Result<xproto::Timestamp> {
+    xproto::change_property(
+        conn,
+        xproto::PROP_MODE_APPEND as u8,
+        window,
+        xproto::ATOM_WM_NAME,
+        xproto::ATOM_STRING,
+        8,
+        &[] as &[u8],
+    )
+    .request_check()?;
+
+    loop {
+        match wait_for_event(conn, None)? {
+            Wait::Event(event) => {
+                if event.response_type() == xproto::PROPERTY_NOTIFY {
+                    let event: &xproto::PropertyNotifyEvent = unsafe { xbase::cast_event(&event) };
+                    if event.window() == window {
+                        return Ok(event.time());
+                    }
+                }
+            }
+            _ => return Err(anyhow!("Could not get a timestamp from the X server")),
+        }
+    }
+}
+
+// Gives up ownership of the `selections` that `window` still owns
+fn release_selections(
+    conn: &Connection,
+    window: xproto::Window,
+    selections: &[xproto::Atom],
+    timestamp: xproto::Timestamp,
+) -> Result<()> {
+    for selection in selections {
+        if xproto::get_selection_owner(conn, *selection)
+            .get_reply()?
+            .owner()
+            == window
+        {
+            xproto::set_selection_owner(conn, xbase::NONE, *selection, timestamp)
+                .request_check()?;
+        }
+    }
+    xproto::destroy_window(conn, window);
+    conn.flush();
+    Ok(())
+}
 
 pub fn set_selection(
     conn: &Connection,
     root: xproto::Window,
-    selection: &Selection,
+    selections: &[Selection],
+    color: ARGB,
     string: &str,
+    options: &SelectionOptions,
 ) -> Result<()> {
-    let selection = selection.to_atom(conn)?;
-    let utf8_string = atoms::get(conn, "UTF8_STRING")?;
-    let targets = atoms::get(conn, "TARGETS")?;
+    let mut selections = selections
+        .iter()
+        .map(|selection| selection.to_atom(conn))
+        .collect::<Result<Vec<_>>>()?;
 
     let window = conn.generate_id();
 
@@ -97,78 +692,173 @@ pub fn set_selection(
         0,                                      // Border
         xproto::WINDOW_CLASS_INPUT_ONLY as u16, // Class
         xbase::COPY_FROM_PARENT,                // Visual
-        &[],
+        &[(xproto::CW_EVENT_MASK, xproto::EVENT_MASK_PROPERTY_CHANGE)],
     )
     .request_check()?;
 
-    // It would be better to use a real timestamp
-    xproto::set_selection_owner(conn, window, selection, xbase::CURRENT_TIME).request_check()?;
+    let timestamp = get_timestamp(conn, window)?;
+    for selection in &selections {
+        xproto::set_selection_owner(conn, window, *selection, timestamp).request_check()?;
 
-    if xproto::get_selection_owner(conn, selection)
-        .get_reply()?
-        .owner()
-        != window
-    {
-        return Err(anyhow!("Could not take selection ownership"));
+        if xproto::get_selection_owner(conn, *selection)
+            .get_reply()?
+            .owner()
+            != window
+        {
+            return Err(anyhow!("Could not take selection ownership"));
+        }
     }
 
+    let mut owner = Owner::new(conn, timestamp, color, string, options.swatch_size);
+
+    // if a clipboard manager takes over CLIPBOARD, we don't need to stay around to serve it
+    let clipboard = Selection::Clipboard.to_atom(conn)?;
+    if selections.contains(&clipboard) {
+        owner.request_save(window)?;
+    }
+
+    // SIGTERM releases the selection instead of leaving it to the X server to clean up after us
+    signal::install()?;
+
+    let deadline = options.lifetime.map(|lifetime| Instant::now() + lifetime);
+
+    // exits when the connection is closed, which also happens when the X session ends
     loop {
-        let event = conn.wait_for_event();
-        if let Some(event) = event {
-            match event.response_type() {
-                xproto::SELECTION_REQUEST => {
-                    let event: &xproto::SelectionRequestEvent =
-                        unsafe { xbase::cast_event(&event) };
-
-                    // We should check the event timestamp
-
-                    let target = event.target();
-                    let property = if target == utf8_string {
-                        xproto::change_property(
-                            conn,
-                            xproto::PROP_MODE_REPLACE as u8,
-                            event.requestor(),
-                            event.property(),
-                            target,
-                            8,
-                            string.as_bytes(),
-                        )
-                        .request_check()?;
-                        event.property()
-                    } else if target == targets {
-                        xproto::change_property(
-                            conn,
-                            xproto::PROP_MODE_REPLACE as u8,
-                            event.requestor(),
-                            event.property(),
-                            target,
-                            32,
-                            &[targets, utf8_string],
-                        )
-                        .request_check()?;
-                        event.property()
-                    } else {
-                        0
-                    };
-
-                    let response = xproto::SelectionNotifyEvent::new(
-                        event.time(),
-                        event.requestor(),
-                        event.selection(),
-                        target,
-                        property,
-                    );
-                    xproto::send_event(conn, false, event.requestor(), 0, &response)
-                        .request_check()?;
+        let event = match wait_for_event(conn, deadline)? {
+            Wait::Event(event) => event,
+            Wait::Interrupted(_) | Wait::TimedOut => {
+                release_selections(conn, window, &selections, timestamp)?;
+                break;
+            }
+            Wait::Closed => break,
+        };
+
+        // the high bit is set on events that come from `SendEvent`, like the
+        // clipboard manager's reply
+        match event.response_type() & !0x80 {
+            xproto::SELECTION_REQUEST => {
+                let event: &xproto::SelectionRequestEvent = unsafe { xbase::cast_event(&event) };
+                owner.handle_request(event);
+            }
+            xproto::PROPERTY_NOTIFY => {
+                let event: &xproto::PropertyNotifyEvent = unsafe { xbase::cast_event(&event) };
+                owner.handle_property_notify(event);
+            }
+            xproto::DESTROY_NOTIFY => {
+                let event: &xproto::DestroyNotifyEvent = unsafe { xbase::cast_event(&event) };
+                owner.handle_destroy_notify(event);
+            }
+            xproto::SELECTION_NOTIFY => {
+                let event: &xproto::SelectionNotifyEvent = unsafe { xbase::cast_event(&event) };
+                let saved = event.selection() == atoms::get(conn, "CLIPBOARD_MANAGER")?
+                    && event.target() == atoms::get(conn, "SAVE_TARGETS")?
+                    && event.property() != xbase::NONE;
+                if saved {
+                    selections.retain(|selection| *selection != clipboard);
+                    if selections.is_empty() {
+                        break;
+                    }
                 }
-                xproto::SELECTION_CLEAR => {
+            }
+            xproto::SELECTION_CLEAR => {
+                // keep serving until another client has taken all of our selections
+                let event: &xproto::SelectionClearEvent = unsafe { xbase::cast_event(&event) };
+                selections.retain(|selection| *selection != event.selection());
+                if selections.is_empty() {
                     break;
                 }
-                _ => {}
             }
-        } else {
+            _ => {}
+        }
+
+        // once pasted enough times, finish the transfers in progress and leave
+        let pasted_enough = options
+            .max_pastes
+            .map_or(false, |max_pastes| owner.pastes >= max_pastes);
+        if pasted_enough && owner.transfers.is_empty() {
+            release_selections(conn, window, &selections, timestamp)?;
             break;
         }
     }
     Ok(())
 }
+
+#[test]
+fn test_text_encodings() {
+    assert_eq!(to_latin1("#ff00ff"), b"#ff00ff");
+    assert_eq!(to_latin1("caf\u{e9} \u{1f308}"), b"caf\xe9 ?");
+    assert_eq!(to_ascii("caf\u{e9}"), b"caf?");
+}
+
+#[test]
+fn test_html() {
+    assert_eq!(escape_html("<a & 'b'>"), "&lt;a &amp; &#39;b&#39;&gt;");
+    assert!(to_html(ARGB::new(0xff, 0x12, 0xab, 0xef), "rgb(1, 2, 3)")
+        .contains("background-color: #12abef;"));
+    assert!(to_html(ARGB::WHITE, "<b>").contains("<code>&lt;b&gt;</code>"));
+}
+
+#[test]
+fn test_x_color() {
+    assert_eq!(
+        to_x_color(ARGB::new(0xff, 0x00, 0x80, 0xff)),
+        [0x0000, 0x8080, 0xffff, 0xffff]
+    );
+}
+
+#[test]
+fn test_chunk_size() {
+    // the usual 256 KiB limit of servers with BIG-REQUESTS
+    assert_eq!(chunk_size(4 * 1024 * 1024), MAX_CHUNK_SIZE);
+    // without BIG-REQUESTS, the header and 4-byte alignment are accounted for
+    assert_eq!(chunk_size(65535), 65535 * 4 - CHANGE_PROPERTY_HEADER_SIZE);
+    assert_eq!(chunk_size(100) % 4, 0);
+}
+
+#[test]
+fn test_incr_chunks() {
+    fn chunks(conversion: Conversion, chunk_size: usize) -> Vec<Vec<u8>> {
+        let mut transfer = Transfer {
+            requestor: 0,
+            property: 0,
+            conversion,
+            offset: 0,
+        };
+        let mut chunks = Vec::new();
+        loop {
+            let chunk = transfer.next_chunk(chunk_size).to_vec();
+            let done = chunk.is_empty();
+            chunks.push(chunk);
+            if done {
+                break chunks;
+            }
+        }
+    }
+
+    // exactly one chunk, then the terminating empty one
+    let data: Vec<u8> = (0..8).collect();
+    let sent = chunks(Conversion::bytes(0, data.clone()), 8);
+    assert_eq!(sent, vec![data.clone(), vec![]]);
+
+    // one byte more spills into a second chunk
+    let data: Vec<u8> = (0..9).collect();
+    let sent = chunks(Conversion::bytes(0, data.clone()), 8);
+    assert_eq!(sent, vec![data[..8].to_vec(), vec![8], vec![]]);
+
+    // 16 and 32-bit items are never split between chunks
+    let sent = chunks(Conversion::shorts(0, &[1, 2, 3]), 4);
+    assert_eq!(sent.iter().map(Vec::len).collect::<Vec<_>>(), [4, 2, 0]);
+    let sent = chunks(Conversion::longs(0, &[1, 2, 3]), 8);
+    assert_eq!(sent.iter().map(Vec::len).collect::<Vec<_>>(), [8, 4, 0]);
+    assert_eq!(sent.concat(), Conversion::longs(0, &[1, 2, 3]).data);
+}
+
+#[test]
+fn test_is_paste() {
+    let data_targets = [1, 2, 3];
+    assert!(is_paste(&[1], &data_targets, false));
+    assert!(is_paste(&[4, 1, 2, 3], &data_targets, false));
+    assert!(!is_paste(&[4], &data_targets, false));
+    assert!(!is_paste(&[], &data_targets, false));
+    assert!(!is_paste(&[1, 2], &data_targets, true));
+}
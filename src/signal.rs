@@ -0,0 +1,66 @@
+use anyhow::Result;
+use nix::fcntl::OFlag;
+use nix::libc::c_int;
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::unistd;
+use std::convert::TryFrom;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+// Self-pipe used to wake up `poll` when a signal arrives: the handler writes
+// the signal's number to `PIPE_WRITE`, and the event loop watches `PIPE_READ`
+static PIPE_READ: AtomicI32 = AtomicI32::new(-1);
+static PIPE_WRITE: AtomicI32 = AtomicI32::new(-1);
+// the last signal read from the pipe, 0 until one is caught
+static CAUGHT: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn on_signal(signal: c_int) {
+    // `write` is async-signal-safe, and there's nothing useful to do if it fails
+    let _ = unistd::write(PIPE_WRITE.load(Ordering::SeqCst), &[signal as u8]);
+}
+
+/// Catches SIGINT and SIGTERM so that they can be handled by the event loop
+/// instead of killing the process. Calling this more than once is harmless.
+pub fn install() -> Result<()> {
+    if PIPE_READ.load(Ordering::SeqCst) >= 0 {
+        return Ok(());
+    }
+
+    let (read, write) = unistd::pipe2(OFlag::O_NONBLOCK | OFlag::O_CLOEXEC)?;
+    PIPE_READ.store(read, Ordering::SeqCst);
+    PIPE_WRITE.store(write, Ordering::SeqCst);
+
+    let action = SigAction::new(
+        SigHandler::Handler(on_signal),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+    for signal in &[Signal::SIGINT, Signal::SIGTERM] {
+        unsafe { sigaction(*signal, &action)? };
+    }
+
+    Ok(())
+}
+
+/// The file descriptor that becomes readable once a signal has been caught.
+pub fn fd() -> Option<RawFd> {
+    match PIPE_READ.load(Ordering::SeqCst) {
+        fd if fd >= 0 => Some(fd),
+        _ => None,
+    }
+}
+
+/// Returns the signal that was caught, if SIGINT or SIGTERM has been.
+pub fn caught() -> Option<Signal> {
+    if let Some(fd) = fd() {
+        let mut buffer = [0; 16];
+        while let Ok(n) = unistd::read(fd, &mut buffer) {
+            if n == 0 {
+                break;
+            }
+            CAUGHT.store(buffer[n - 1].into(), Ordering::SeqCst);
+        }
+    }
+    // 0 isn't a signal
+    Signal::try_from(CAUGHT.load(Ordering::SeqCst)).ok()
+}
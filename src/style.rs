@@ -0,0 +1,66 @@
+use anyhow::{anyhow, Error, Result};
+use std::str::FromStr;
+
+use crate::color::ARGB;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Shape {
+    Circle,
+    Square,
+    RoundedSquare,
+}
+
+impl FromStr for Shape {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "circle" => Ok(Shape::Circle),
+            "square" => Ok(Shape::Square),
+            "rounded" => Ok(Shape::RoundedSquare),
+            _ => Err(anyhow!("Invalid shape")),
+        }
+    }
+}
+
+/// How the pixel under the pointer is marked in the magnifier.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Highlight {
+    Box,
+    Crosshair,
+}
+
+impl FromStr for Highlight {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "box" => Ok(Highlight::Box),
+            "crosshair" => Ok(Highlight::Crosshair),
+            _ => Err(anyhow!("Invalid highlight")),
+        }
+    }
+}
+
+/// The look of the magnifier.
+pub struct Style {
+    pub shape: Shape,
+    pub border_width: u32,
+    /// Color of the border, contrasting with the pixels underneath when `None`
+    pub border_color: Option<ARGB>,
+    pub grid: bool,
+    /// How much grid lines are lightened or darkened, between 0 and 1
+    pub grid_opacity: f32,
+    pub highlight: Highlight,
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        Style {
+            shape: Shape::Circle,
+            border_width: 1,
+            border_color: None,
+            grid: true,
+            grid_opacity: 0.2,
+            highlight: Highlight::Box,
+        }
+    }
+}
@@ -1,3 +1,7 @@
+use anyhow::{anyhow, Result};
+use std::time::Duration;
+use xcb::base::{Connection, Extension};
+
 /// A simple trait which makes it easy to ensure a given type is odd.
 pub trait EnsureOdd {
     fn ensure_odd(self) -> Self;
@@ -22,3 +26,51 @@ impl_ensure_odd!(u16);
 impl_ensure_odd!(u32);
 impl_ensure_odd!(isize);
 impl_ensure_odd!(usize);
+
+/// Returns whether the X server supports `extension`. Requests to a missing
+/// extension close the connection, so this has to be checked before sending any.
+pub fn has_extension(conn: &Connection, extension: &mut Extension) -> bool {
+    conn.get_extension_data(extension)
+        .map(|data| data.present())
+        .unwrap_or(false)
+}
+
+/// Parses a duration such as `1.5`, `500ms`, `30s`, `2m` or `1h`. Numbers without a unit are
+/// interpreted as seconds.
+pub fn parse_duration(string: &str) -> Result<Duration> {
+    let string = string.trim();
+    let split = string
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(string.len());
+    let (value, unit) = string.split_at(split);
+
+    let value = value
+        .parse::<f64>()
+        .map_err(|_| anyhow!("Invalid duration"))?;
+    let multiplier = match unit.trim() {
+        "ms" => 0.001,
+        "" | "s" => 1.0,
+        "m" => 60.0,
+        "h" => 60.0 * 60.0,
+        _ => return Err(anyhow!("Invalid duration unit")),
+    };
+
+    // also rejects durations too long to represent, which would otherwise panic
+    Duration::try_from_secs_f64(value * multiplier).map_err(|_| anyhow!("Invalid duration"))
+}
+
+#[test]
+fn test_parse_duration() {
+    assert_eq!(parse_duration("10").unwrap(), Duration::from_secs(10));
+    assert_eq!(parse_duration("1.5").unwrap(), Duration::from_millis(1500));
+    assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+    assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+    assert_eq!(parse_duration("2m").unwrap(), Duration::from_secs(120));
+    assert_eq!(parse_duration("1h").unwrap(), Duration::from_secs(3600));
+    assert!(parse_duration("").is_err());
+    assert!(parse_duration("s").is_err());
+    assert!(parse_duration("-1").is_err());
+    assert!(parse_duration("10d").is_err());
+    assert!(parse_duration("100000000000000000000").is_err());
+    assert!(parse_duration("99999999999999999999h").is_err());
+}
@@ -0,0 +1,90 @@
+use anyhow::Result;
+use std::io::{self, Write};
+use std::thread;
+use std::time::Duration;
+use xcb::base::Connection;
+use xcb::xproto;
+
+use crate::color::{self, ARGB};
+use crate::format::FormatColor;
+use crate::location::query_pointer;
+
+// How often the pointer is sampled when only printing changes
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+fn json_escape(string: &str) -> String {
+    let mut escaped = String::with_capacity(string.len());
+    for c in string.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Prints the color under the pointer without grabbing it. If `interval` is
+// given, a line is printed every `interval`, otherwise only when the color or
+// (in JSON mode) the pointer position changes. Returns once stdout is closed.
+pub fn watch(
+    conn: &Connection,
+    screen: &xproto::Screen,
+    formatter: &dyn FormatColor,
+    interval: Option<Duration>,
+    json: bool,
+) -> Result<()> {
+    let root = screen.root();
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let mut last: Option<((i16, i16), ARGB)> = None;
+
+    loop {
+        let point = query_pointer(conn, root)?;
+        let color = color::window_rect(conn, root, (point.0, point.1, 1, 1))?[0];
+
+        let changed = match last {
+            Some((last_point, last_color)) => last_color != color || (json && last_point != point),
+            None => true,
+        };
+
+        if interval.is_some() || changed {
+            let output = formatter.format(color);
+            let line = if json {
+                format!(
+                    "{{\"x\":{},\"y\":{},\"color\":\"{}\"}}",
+                    point.0,
+                    point.1,
+                    json_escape(&output)
+                )
+            } else {
+                output
+            };
+
+            // the reader went away (e.g. the status bar was restarted), which is not an error
+            if writeln!(stdout, "{}", line)
+                .and_then(|_| stdout.flush())
+                .is_err()
+            {
+                break;
+            }
+            last = Some((point, color));
+        }
+
+        thread::sleep(interval.unwrap_or(POLL_INTERVAL));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_json_escape() {
+    assert_eq!(json_escape("#ff00ff"), "#ff00ff");
+    assert_eq!(json_escape("\"a\"\\b"), "\\\"a\\\"\\\\b");
+    assert_eq!(json_escape("a\tb\n"), "a\\tb\\n");
+    assert_eq!(json_escape("\u{1}"), "\\u0001");
+}